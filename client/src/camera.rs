@@ -0,0 +1,81 @@
+use glium::glutin::VirtualKeyCode;
+use xmath::Matrix;
+
+/// Game units shown per pixel at the default zoom.
+const UNITS_PER_PIXEL: f32 = 0.1;
+/// How fast the camera pans, in screen widths per second.
+const PAN_SPEED: f32 = 0.5;
+const ZOOM_STEP: f32 = 1.25;
+const MIN_ZOOM: f32 = 0.25;
+const MAX_ZOOM: f32 = 4.0;
+
+/// Camera of spectators, who look around freely instead of following a unit. Arrow keys pan it,
+/// Page Up and Page Down zoom it.
+pub struct FreeCamera {
+    width: u32,
+    height: u32,
+    center: (f32, f32),
+    zoom: f32,
+    /// Arrow keys held down, as left, right, down, up.
+    held: [bool; 4],
+}
+
+impl FreeCamera {
+    pub fn new(width: u32, height: u32) -> Self {
+        FreeCamera {
+            width,
+            height,
+            center: (0.0, 0.0),
+            zoom: 1.0,
+            held: [false; 4],
+        }
+    }
+
+    /// Handles a key press or release. Other keys than arrows and Page Up/Down are ignored.
+    pub fn key(&mut self, pressed: bool, key: VirtualKeyCode) {
+        let arrow = match key {
+            VirtualKeyCode::Left => 0,
+            VirtualKeyCode::Right => 1,
+            VirtualKeyCode::Down => 2,
+            VirtualKeyCode::Up => 3,
+            VirtualKeyCode::PageUp => {
+                if pressed {
+                    self.zoom = (self.zoom * ZOOM_STEP).min(MAX_ZOOM);
+                }
+                return;
+            }
+            VirtualKeyCode::PageDown => {
+                if pressed {
+                    self.zoom = (self.zoom / ZOOM_STEP).max(MIN_ZOOM);
+                }
+                return;
+            }
+            _ => return,
+        };
+        self.held[arrow] = pressed;
+    }
+
+    /// Pans along the arrow keys held down for `delta` seconds.
+    pub fn update(&mut self, delta: f32) {
+        let axis = |negative: bool, positive: bool| match (negative, positive) {
+            (true, false) => -1.0,
+            (false, true) => 1.0,
+            _ => 0.0,
+        };
+        let speed = PAN_SPEED * self.width as f32 * UNITS_PER_PIXEL / self.zoom * delta;
+        self.center.0 += axis(self.held[0], self.held[1]) * speed;
+        self.center.1 += axis(self.held[2], self.held[3]) * speed;
+    }
+
+    pub fn matrix(&self) -> Matrix {
+        let scale = UNITS_PER_PIXEL / self.zoom;
+        let view = Matrix::translation(-self.center.0, -self.center.1, 0.0);
+        let projection = Matrix::orthographic(
+            self.width as f32 * scale,
+            self.height as f32 * scale,
+            0.0,
+            1.0,
+        );
+        view * projection
+    }
+}
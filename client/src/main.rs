@@ -7,20 +7,31 @@ extern crate bincode;
 extern crate obj;
 extern crate rand;
 
+mod camera;
 mod draw_context;
 mod error;
 mod resource;
+mod spectator;
 mod traits;
 mod ui;
 mod units;
 
+use crate::camera::FreeCamera;
 use crate::draw_context::DrawContext;
+use crate::spectator::Spectator;
 use crate::ui::UI;
-use crate::units::{Minion, MinionController, Nemo};
+use crate::units::{Minion, MinionController, Nemo, Streamed};
+use std::net::{SocketAddr, ToSocketAddrs};
 use time::PreciseTime;
 
 #[cfg_attr(test, allow(dead_code))]
 fn main() {
+    // Spectators watch a game streamed from the server, with a free camera and no unit control
+    let mut spectator = spectate_args().map(|(server, game_id)| {
+        Spectator::connect(server, game_id).expect("Failed to reach the server")
+    });
+    let spectating = spectator.is_some();
+
     // Make a render targets
     let (width, height) = (1024, 768);
 
@@ -43,7 +54,8 @@ fn main() {
     })();
 
     // TODO: Error 처리
-    let draw_context = DrawContext::new(&display, width, height).unwrap();
+    let mut draw_context = DrawContext::new(&display, width, height).unwrap();
+    let mut camera = FreeCamera::new(width, height);
 
     //
     // Game
@@ -57,6 +69,7 @@ fn main() {
         Minion::new(&display, (-17.0, -4.0)).unwrap(),
     ];
     let mut controller = MinionController::new(&display).unwrap();
+    let mut streamed = Streamed::new();
 
     //
     // Parameters for UI
@@ -80,7 +93,7 @@ fn main() {
 
             match event {
                 Event::MouseMoved(x, y) => ui.move_cursor(x, y),
-                Event::MouseInput(ElementState::Pressed, MouseButton::Left) => {
+                Event::MouseInput(ElementState::Pressed, MouseButton::Left) if !spectating => {
                     use crate::traits::Move;
 
                     // 마우스 좌표계 ~ 게임 좌표계 변환
@@ -92,16 +105,22 @@ fn main() {
                     let texture = &draw_context.texture_for_object_picking;
                     let mut object_picking_buffer = texture.as_surface();
                     // TODO: 예외처리
-                    nemo.fill(&mut object_picking_buffer, &draw_context)
-                        .unwrap();
-                    for minion in &minions {
-                        minion
+                    if spectating {
+                        streamed
+                            .fill(&mut object_picking_buffer, &draw_context)
+                            .unwrap();
+                    } else {
+                        nemo.fill(&mut object_picking_buffer, &draw_context)
+                            .unwrap();
+                        for minion in &minions {
+                            minion
+                                .fill(&mut object_picking_buffer, &draw_context)
+                                .unwrap();
+                        }
+                        controller
                             .fill(&mut object_picking_buffer, &draw_context)
                             .unwrap();
                     }
-                    controller
-                        .fill(&mut object_picking_buffer, &draw_context)
-                        .unwrap();
                     let buffer = texture.read_to_pixel_buffer();
                     let pixel_index = (width as f32 * ui.cursor.1 + ui.cursor.0) as usize;
                     let pixel_color = buffer
@@ -112,7 +131,12 @@ fn main() {
 
                     println!("{:?} {:?}", ui.cursor, color_to_id(&pixel_color));
                 }
-                Event::KeyboardInput(ElementState::Pressed, _, Some(vkey::Q)) => nemo.q(),
+                Event::KeyboardInput(state, _, Some(key)) if spectating => {
+                    camera.key(state == ElementState::Pressed, key);
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(vkey::Q)) if !spectating => {
+                    nemo.q()
+                }
                 Event::Closed => break 'main,
                 _ => (),
             }
@@ -126,11 +150,21 @@ fn main() {
         let delta = last.to(now).num_nanoseconds().unwrap() as f32 / 1.0E+9;
         last = now;
 
-        nemo.update(delta);
-        for m in &mut minions {
-            m.update(delta);
+        if let Some(ref mut spectator) = spectator {
+            if let Err(e) = spectator.poll() {
+                eprintln!("couldn't receive from the server: {}", e);
+            }
+            // TODO: Error 처리
+            streamed.show(&display, spectator.units()).unwrap();
+            camera.update(delta);
+            draw_context.camera = camera.matrix();
+        } else {
+            nemo.update(delta);
+            for m in &mut minions {
+                m.update(delta);
+            }
+            controller.update(delta);
         }
-        controller.update(delta);
 
         //
         // Render
@@ -138,17 +172,49 @@ fn main() {
         let mut target = display.draw();
         target.clear_color_and_depth((0.0, 0.0, 0.0, 0.0), 1.0);
 
-        nemo.draw(&mut target, &draw_context).unwrap();
-        for minion in &minions {
-            minion.draw(&mut target, &draw_context).unwrap();
+        if spectating {
+            streamed.draw(&mut target, &draw_context).unwrap();
+        } else {
+            nemo.draw(&mut target, &draw_context).unwrap();
+            for minion in &minions {
+                minion.draw(&mut target, &draw_context).unwrap();
+            }
+            controller.draw(&mut target, &draw_context).unwrap();
         }
-        controller.draw(&mut target, &draw_context).unwrap();
 
         ui.draw(&mut target).unwrap();
         let _ = target.finish();
     }
 }
 
+/// The server and game to watch, from `--spectate <game id>` and `--server <host:port>`, or `None`
+/// to play locally.
+fn spectate_args() -> Option<(SocketAddr, usize)> {
+    let mut server = "127.0.0.1:4567".to_string();
+    let mut game_id = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .unwrap_or_else(|| panic!("`{}` needs a value", flag));
+        match &flag[..] {
+            "--server" => server = value,
+            "--spectate" => match value.parse() {
+                Ok(id) => game_id = Some(id),
+                Err(_) => panic!("invalid game id `{}`", value),
+            },
+            _ => panic!("unknown flag `{}`", flag),
+        }
+    }
+
+    let addr = match server.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        _ => panic!("couldn't resolve `{}`", server),
+    };
+    game_id.map(|game_id| (addr, game_id))
+}
+
 fn color_to_id(color: &(u8, u8, u8, u8)) -> Option<u32> {
     match *color {
         (255, 255, 255, 255) => None,
@@ -0,0 +1,130 @@
+use common::codec::Codec;
+use common::message::{
+    ClientToServer, Message, Request, RequestId, Response, ServerToClient, SessionToken,
+};
+use common::simulation::Unit;
+use common::snapshot::SnapshotReceiver;
+use common::transport::{Channel, Endpoint, Event};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How often to ping the server while no snapshot comes, so that it doesn't drop us as idle.
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connection of a spectator to the server, streaming the units of the watched game.
+pub struct Spectator {
+    endpoint: Endpoint,
+    server: SocketAddr,
+    game_id: usize,
+    /// Who the server knows us as, once it answered the handshake.
+    session: Option<(usize, SessionToken)>,
+    next_request_id: RequestId,
+    last_sent: Instant,
+    receiver: SnapshotReceiver,
+    units: Vec<Unit>,
+}
+
+impl Spectator {
+    /// Connects to `server`, asking to watch game `game_id` once it lets us in.
+    pub fn connect(server: SocketAddr, game_id: usize) -> io::Result<Self> {
+        let mut spectator = Spectator {
+            endpoint: Endpoint::bind("0.0.0.0:0")?,
+            server,
+            game_id,
+            session: None,
+            next_request_id: 0,
+            last_sent: Instant::now(),
+            receiver: SnapshotReceiver::new(),
+            units: Vec::new(),
+        };
+        spectator.send(ClientToServer::connect_request(), Channel::Reliable);
+        Ok(spectator)
+    }
+
+    /// The units of the latest snapshot, empty until the game is in progress.
+    pub fn units(&self) -> &[Unit] {
+        &self.units
+    }
+
+    /// Handles whatever the server sent since the last call, without waiting for more.
+    pub fn poll(&mut self) -> io::Result<()> {
+        while let Some(event) = self.endpoint.recv(Some(Duration::from_millis(1)))? {
+            match event {
+                Event::Message { addr, payload, .. } if addr == self.server => {
+                    match Response::decode(&payload) {
+                        Ok((response, _)) => self.handle(response.message),
+                        Err(err) => eprintln!("{:?} when parsing a datagram", err),
+                    }
+                }
+                Event::Message { .. } => {}
+                Event::Timeout { .. } => eprintln!("The server doesn't acknowledge our messages."),
+            }
+        }
+        if self.session.is_some() && self.last_sent.elapsed() >= PING_INTERVAL {
+            let user_id = self.session.as_ref().unwrap().0;
+            self.send(ClientToServer::Ping { user_id }, Channel::Reliable);
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, message: ServerToClient) {
+        match message {
+            ServerToClient::ConnectResponse { user_id, token } => {
+                self.session = Some((user_id, token));
+                let game_id = self.game_id;
+                self.send(
+                    ClientToServer::SpectateRequest { user_id, game_id },
+                    Channel::Reliable,
+                );
+            }
+            ServerToClient::SpectateResponse {
+                game_id,
+                delay_ticks,
+            } => println!("Watching game {}, {} ticks late", game_id, delay_ticks),
+            ServerToClient::GameStateChanged { game_id, state } => {
+                println!("Game {} is {:?}", game_id, state)
+            }
+            ServerToClient::Snapshot { game_id, snapshot } if game_id == self.game_id => {
+                let user_id = match self.session {
+                    Some((user_id, _)) => user_id,
+                    None => return,
+                };
+                if let Some(units) = self.receiver.receive(&snapshot) {
+                    self.units = units.to_vec();
+                    let tick = snapshot.tick;
+                    // Losing an ack only costs a bigger snapshot, so it needn't be reliable.
+                    self.send(
+                        ClientToServer::SnapshotAck {
+                            user_id,
+                            game_id,
+                            tick,
+                        },
+                        Channel::Unreliable,
+                    );
+                }
+            }
+            ServerToClient::Error { code, message } => eprintln!("Error {:?}: {}", code, message),
+            ServerToClient::ServerShuttingDown { reason, .. } => {
+                println!("The server is shutting down: {}", reason)
+            }
+            _ => {}
+        }
+    }
+
+    /// Sends a command to the server. Failures are only reported, since anything lost is either
+    /// retried by the transport or superseded by the next snapshot.
+    fn send(&mut self, command: ClientToServer, channel: Channel) {
+        let request = Request {
+            id: self.next_request_id,
+            token: self.session.as_ref().map(|(_, token)| token.clone()),
+            command,
+        };
+        self.next_request_id += 1;
+        let buf = request.encode(Codec::Binary).unwrap();
+        self.last_sent = Instant::now();
+        if let Err(e) = self.endpoint.send(self.server, channel, &buf) {
+            eprintln!("couldn't send the request: {:?}", e);
+        }
+    }
+}
@@ -50,6 +50,13 @@ impl Minion {
             state: State::Stopped { time: 0.0 },
         })
     }
+
+    /// Puts the minion where the server says it is, for games streamed from the server.
+    pub fn place(&mut self, pos: (f32, f32), angle: f32) {
+        self.unit.pos = pos;
+        self.unit.angle = angle;
+        self.state = State::Stopped { time: 0.0 };
+    }
 }
 
 impl Object for Minion {
@@ -1,8 +1,10 @@
 mod minion;
 mod nemo;
+mod streamed;
 
 pub use self::minion::{Minion, MinionController};
 pub use self::nemo::Nemo;
+pub use self::streamed::Streamed;
 use crate::draw_context::DrawContext;
 use crate::error::CreationError;
 use glium::backend::Facade;
@@ -139,4 +139,15 @@ impl Nemo {
     pub fn q(&mut self) {
         self.state = State::QSkill { t: 0.0 };
     }
+
+    /// Puts Nemo where the server says it is, for games streamed from the server.
+    pub fn place(&mut self, pos: (f32, f32), angle: f32, using_q: bool) {
+        self.unit.pos = pos;
+        self.unit.angle = angle;
+        self.state = if using_q {
+            State::QSkill { t: 0.0 }
+        } else {
+            State::Stopped
+        };
+    }
 }
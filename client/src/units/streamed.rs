@@ -0,0 +1,84 @@
+use super::{Minion, Nemo};
+use crate::draw_context::DrawContext;
+use crate::error::CreationError;
+use crate::traits::Object;
+use common::simulation::{self, UnitId, UnitKind, UnitState};
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::{DrawError, Frame};
+use std::collections::HashMap;
+
+/// The units of a game streamed from the server, each drawn where the latest snapshot puts it.
+#[derive(Default)]
+pub struct Streamed {
+    nemos: HashMap<UnitId, Nemo>,
+    minions: HashMap<UnitId, Minion>,
+}
+
+impl Streamed {
+    pub fn new() -> Self {
+        Streamed::default()
+    }
+
+    /// Shows `units` from now on. Models are made for new units and dropped for the gone ones.
+    pub fn show<F: Facade>(
+        &mut self,
+        facade: &F,
+        units: &[simulation::Unit],
+    ) -> Result<(), CreationError> {
+        let shown = |id: &UnitId| units.iter().any(|unit| unit.id == *id);
+        self.nemos.retain(|id, _| shown(id));
+        self.minions.retain(|id, _| shown(id));
+
+        for unit in units {
+            match unit.kind {
+                UnitKind::Nemo { .. } => {
+                    if !self.nemos.contains_key(&unit.id) {
+                        self.nemos.insert(unit.id, Nemo::new(facade)?);
+                    }
+                    let using_q = matches!(unit.state, UnitState::QSkill { .. });
+                    let nemo = self.nemos.get_mut(&unit.id).unwrap();
+                    nemo.place(unit.pos, unit.angle, using_q);
+                }
+                UnitKind::Minion => {
+                    if !self.minions.contains_key(&unit.id) {
+                        self.minions.insert(unit.id, Minion::new(facade, unit.pos)?);
+                    }
+                    let minion = self.minions.get_mut(&unit.id).unwrap();
+                    minion.place(unit.pos, unit.angle);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Object for Streamed {
+    fn update(&mut self, _: f32) {
+        // The server moves the units, see `show`.
+    }
+
+    fn draw(&self, target: &mut Frame, draw_context: &DrawContext) -> Result<(), DrawError> {
+        for nemo in self.nemos.values() {
+            nemo.draw(target, draw_context)?
+        }
+        for minion in self.minions.values() {
+            minion.draw(target, draw_context)?
+        }
+        Ok(())
+    }
+
+    fn fill(
+        &self,
+        target: &mut SimpleFrameBuffer,
+        draw_context: &DrawContext,
+    ) -> Result<(), DrawError> {
+        for nemo in self.nemos.values() {
+            nemo.fill(target, draw_context)?
+        }
+        for minion in self.minions.values() {
+            minion.fill(target, draw_context)?
+        }
+        Ok(())
+    }
+}
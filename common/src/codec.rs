@@ -0,0 +1,700 @@
+//! Wire codecs for messages.
+//!
+//! Two encodings are supported. `Json` is the human readable one, which is handy when poking at
+//! the server with `nc` or `commander`. `Binary` is a compact, versioned encoding meant for real
+//! clients. A binary frame looks like below, and everything else is treated as JSON text.
+//!
+//! ```text
+//! +-------+---------+----------------+-------------------+
+//! | magic | version | length (u32le) | payload (length)  |
+//! +-------+---------+----------------+-------------------+
+//! ```
+//!
+//! Integers in the payload are LEB128 varints (signed ones are zigzag encoded first), floats are
+//! little endian, strings and sequences are prefixed with their length, and enum variants are
+//! written as their index. Reordering variants or fields is therefore a protocol break.
+
+use rustc_serialize::json;
+use rustc_serialize::{Decodable, Encodable};
+use std::str;
+
+/// First byte of every binary frame. It can never start a JSON text.
+pub const BINARY_MAGIC: u8 = 0xFA;
+/// Version of the binary payload layout.
+pub const BINARY_VERSION: u8 = 1;
+/// Size of the binary frame header: magic, version and length.
+pub const BINARY_HEADER_LEN: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    Json,
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    JsonEncoderError(json::EncoderError),
+    JsonDecoderError(json::DecoderError),
+    InvalidUtf8(str::Utf8Error),
+    /// The frame ended before the value was fully decoded.
+    UnexpectedEof,
+    /// Bytes are left over after the value was fully decoded.
+    TrailingBytes(usize),
+    /// The frame header declares a version this build cannot read.
+    UnsupportedVersion(u8),
+    /// The frame header declares a different length than what was received.
+    LengthMismatch {
+        declared: usize,
+        actual: usize,
+    },
+    /// A varint doesn't fit in the requested integer type.
+    Overflow,
+    InvalidChar(u32),
+    InvalidVariant {
+        index: usize,
+        count: usize,
+    },
+    Custom(String),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl From<json::EncoderError> for Error {
+    fn from(err: json::EncoderError) -> Self {
+        Error::JsonEncoderError(err)
+    }
+}
+
+impl From<json::DecoderError> for Error {
+    fn from(err: json::DecoderError) -> Self {
+        Error::JsonDecoderError(err)
+    }
+}
+
+impl From<str::Utf8Error> for Error {
+    fn from(err: str::Utf8Error) -> Self {
+        Error::InvalidUtf8(err)
+    }
+}
+
+impl Codec {
+    /// Guesses the codec of a received datagram from its first byte.
+    pub fn detect(buf: &[u8]) -> Codec {
+        match buf.first() {
+            Some(&BINARY_MAGIC) => Codec::Binary,
+            _ => Codec::Json,
+        }
+    }
+
+    pub fn encode<T: Encodable>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::Json => Ok(json::encode(value)?.into_bytes()),
+            Codec::Binary => {
+                let mut encoder = BinaryEncoder {
+                    buf: vec![0; BINARY_HEADER_LEN],
+                };
+                value.encode(&mut encoder)?;
+
+                let mut buf = encoder.buf;
+                let len = (buf.len() - BINARY_HEADER_LEN) as u32;
+                buf[0] = BINARY_MAGIC;
+                buf[1] = BINARY_VERSION;
+                buf[2..BINARY_HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+                Ok(buf)
+            }
+        }
+    }
+
+    pub fn decode<T: Decodable>(self, buf: &[u8]) -> Result<T> {
+        match self {
+            Codec::Json => Ok(json::decode(str::from_utf8(buf)?.trim_end())?),
+            Codec::Binary => {
+                if buf.len() < BINARY_HEADER_LEN {
+                    return Err(Error::UnexpectedEof);
+                }
+                if buf[1] != BINARY_VERSION {
+                    return Err(Error::UnsupportedVersion(buf[1]));
+                }
+                let mut len = [0u8; 4];
+                len.copy_from_slice(&buf[2..BINARY_HEADER_LEN]);
+                let declared = u32::from_le_bytes(len) as usize;
+                let payload = &buf[BINARY_HEADER_LEN..];
+                if declared != payload.len() {
+                    return Err(Error::LengthMismatch {
+                        declared,
+                        actual: payload.len(),
+                    });
+                }
+
+                let mut decoder = BinaryDecoder { buf: payload };
+                let value = T::decode(&mut decoder)?;
+                match decoder.buf.len() {
+                    0 => Ok(value),
+                    left => Err(Error::TrailingBytes(left)),
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a datagram, detecting its codec. The codec is returned too, so that the reply can be
+/// sent back in the same encoding.
+pub fn decode<T: Decodable>(buf: &[u8]) -> Result<(T, Codec)> {
+    let codec = Codec::detect(buf);
+    codec.decode(buf).map(|value| (value, codec))
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+struct BinaryEncoder {
+    buf: Vec<u8>,
+}
+
+impl BinaryEncoder {
+    fn varint(&mut self, mut v: u64) -> Result<()> {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+        Ok(())
+    }
+}
+
+type EncodeResult = Result<()>;
+
+impl rustc_serialize::Encoder for BinaryEncoder {
+    type Error = Error;
+
+    fn emit_nil(&mut self) -> EncodeResult {
+        Ok(())
+    }
+    fn emit_usize(&mut self, v: usize) -> EncodeResult {
+        self.varint(v as u64)
+    }
+    fn emit_u64(&mut self, v: u64) -> EncodeResult {
+        self.varint(v)
+    }
+    fn emit_u32(&mut self, v: u32) -> EncodeResult {
+        self.varint(u64::from(v))
+    }
+    fn emit_u16(&mut self, v: u16) -> EncodeResult {
+        self.varint(u64::from(v))
+    }
+    fn emit_u8(&mut self, v: u8) -> EncodeResult {
+        self.buf.push(v);
+        Ok(())
+    }
+    fn emit_isize(&mut self, v: isize) -> EncodeResult {
+        self.varint(zigzag(v as i64))
+    }
+    fn emit_i64(&mut self, v: i64) -> EncodeResult {
+        self.varint(zigzag(v))
+    }
+    fn emit_i32(&mut self, v: i32) -> EncodeResult {
+        self.varint(zigzag(i64::from(v)))
+    }
+    fn emit_i16(&mut self, v: i16) -> EncodeResult {
+        self.varint(zigzag(i64::from(v)))
+    }
+    fn emit_i8(&mut self, v: i8) -> EncodeResult {
+        self.buf.push(v as u8);
+        Ok(())
+    }
+    fn emit_bool(&mut self, v: bool) -> EncodeResult {
+        self.buf.push(v as u8);
+        Ok(())
+    }
+    fn emit_f64(&mut self, v: f64) -> EncodeResult {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+    fn emit_f32(&mut self, v: f32) -> EncodeResult {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+    fn emit_char(&mut self, v: char) -> EncodeResult {
+        self.varint(u64::from(v as u32))
+    }
+    fn emit_str(&mut self, v: &str) -> EncodeResult {
+        self.varint(v.len() as u64)?;
+        self.buf.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn emit_enum<F>(&mut self, _name: &str, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_enum_variant<F>(&mut self, _name: &str, id: usize, _len: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        self.varint(id as u64)?;
+        f(self)
+    }
+    fn emit_enum_variant_arg<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_enum_struct_variant<F>(
+        &mut self,
+        name: &str,
+        id: usize,
+        len: usize,
+        f: F,
+    ) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        self.emit_enum_variant(name, id, len, f)
+    }
+    fn emit_enum_struct_variant_field<F>(&mut self, _name: &str, _idx: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+
+    fn emit_struct<F>(&mut self, _name: &str, _len: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_struct_field<F>(&mut self, _name: &str, _idx: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_tuple<F>(&mut self, _len: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_tuple_arg<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_tuple_struct<F>(&mut self, _name: &str, _len: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_tuple_struct_arg<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+
+    fn emit_option<F>(&mut self, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_option_none(&mut self) -> EncodeResult {
+        self.buf.push(0);
+        Ok(())
+    }
+    fn emit_option_some<F>(&mut self, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        self.buf.push(1);
+        f(self)
+    }
+    fn emit_seq<F>(&mut self, len: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        self.varint(len as u64)?;
+        f(self)
+    }
+    fn emit_seq_elt<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_map<F>(&mut self, len: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        self.varint(len as u64)?;
+        f(self)
+    }
+    fn emit_map_elt_key<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+    fn emit_map_elt_val<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+    where
+        F: FnOnce(&mut Self) -> EncodeResult,
+    {
+        f(self)
+    }
+}
+
+struct BinaryDecoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> BinaryDecoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(Error::UnexpectedEof);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7F);
+            if shift == 63 && bits > 1 {
+                return Err(Error::Overflow);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::Overflow)
+    }
+
+    fn varint_max(&mut self, max: u64) -> Result<u64> {
+        match self.varint()? {
+            v if v <= max => Ok(v),
+            _ => Err(Error::Overflow),
+        }
+    }
+
+    fn signed_varint(&mut self, min: i64, max: i64) -> Result<i64> {
+        match unzigzag(self.varint()?) {
+            v if min <= v && v <= max => Ok(v),
+            _ => Err(Error::Overflow),
+        }
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        // Every element takes at least a byte, so a length longer than the rest of the frame is
+        // bogus. Checking it here keeps a hostile frame from making us preallocate gigabytes.
+        let len = self.varint_max(usize::MAX as u64)? as usize;
+        if len > self.buf.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        Ok(len)
+    }
+}
+
+impl<'a> rustc_serialize::Decoder for BinaryDecoder<'a> {
+    type Error = Error;
+
+    fn read_nil(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn read_usize(&mut self) -> Result<usize> {
+        self.varint_max(usize::MAX as u64).map(|v| v as usize)
+    }
+    fn read_u64(&mut self) -> Result<u64> {
+        self.varint()
+    }
+    fn read_u32(&mut self) -> Result<u32> {
+        self.varint_max(u64::from(u32::MAX)).map(|v| v as u32)
+    }
+    fn read_u16(&mut self) -> Result<u16> {
+        self.varint_max(u64::from(u16::MAX)).map(|v| v as u16)
+    }
+    fn read_u8(&mut self) -> Result<u8> {
+        self.byte()
+    }
+    fn read_isize(&mut self) -> Result<isize> {
+        self.signed_varint(isize::MIN as i64, isize::MAX as i64)
+            .map(|v| v as isize)
+    }
+    fn read_i64(&mut self) -> Result<i64> {
+        self.signed_varint(i64::MIN, i64::MAX)
+    }
+    fn read_i32(&mut self) -> Result<i32> {
+        self.signed_varint(i64::from(i32::MIN), i64::from(i32::MAX))
+            .map(|v| v as i32)
+    }
+    fn read_i16(&mut self) -> Result<i16> {
+        self.signed_varint(i64::from(i16::MIN), i64::from(i16::MAX))
+            .map(|v| v as i16)
+    }
+    fn read_i8(&mut self) -> Result<i8> {
+        self.byte().map(|v| v as i8)
+    }
+    fn read_bool(&mut self) -> Result<bool> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(Error::Custom(format!("invalid bool {}", v))),
+        }
+    }
+    fn read_f64(&mut self) -> Result<f64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(f64::from_le_bytes(bytes))
+    }
+    fn read_f32(&mut self) -> Result<f32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(f32::from_le_bytes(bytes))
+    }
+    fn read_char(&mut self) -> Result<char> {
+        let v = self.read_u32()?;
+        ::std::char::from_u32(v).ok_or(Error::InvalidChar(v))
+    }
+    fn read_str(&mut self) -> Result<String> {
+        let len = self.len()?;
+        let bytes = self.bytes(len)?;
+        Ok(str::from_utf8(bytes)?.to_string())
+    }
+
+    fn read_enum<T, F>(&mut self, _name: &str, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_enum_variant<T, F>(&mut self, names: &[&str], mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Self, usize) -> Result<T>,
+    {
+        let index = self.read_usize()?;
+        if index >= names.len() {
+            return Err(Error::InvalidVariant {
+                index,
+                count: names.len(),
+            });
+        }
+        f(self, index)
+    }
+    fn read_enum_variant_arg<T, F>(&mut self, _idx: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_enum_struct_variant<T, F>(&mut self, names: &[&str], f: F) -> Result<T>
+    where
+        F: FnMut(&mut Self, usize) -> Result<T>,
+    {
+        self.read_enum_variant(names, f)
+    }
+    fn read_enum_struct_variant_field<T, F>(&mut self, _name: &str, _idx: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+
+    fn read_struct<T, F>(&mut self, _name: &str, _len: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_struct_field<T, F>(&mut self, _name: &str, _idx: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_tuple<T, F>(&mut self, _len: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_tuple_arg<T, F>(&mut self, _idx: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_tuple_struct<T, F>(&mut self, _name: &str, _len: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_tuple_struct_arg<T, F>(&mut self, _idx: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+
+    fn read_option<T, F>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Self, bool) -> Result<T>,
+    {
+        let some = self.read_bool()?;
+        f(self, some)
+    }
+    fn read_seq<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self, usize) -> Result<T>,
+    {
+        let len = self.len()?;
+        f(self, len)
+    }
+    fn read_seq_elt<T, F>(&mut self, _idx: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_map<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self, usize) -> Result<T>,
+    {
+        let len = self.len()?;
+        f(self, len)
+    }
+    fn read_map_elt_key<T, F>(&mut self, _idx: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+    fn read_map_elt_val<T, F>(&mut self, _idx: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        f(self)
+    }
+
+    fn error(&mut self, err: &str) -> Error {
+        Error::Custom(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, Codec, Error, BINARY_MAGIC, BINARY_VERSION};
+
+    #[derive(RustcDecodable, RustcEncodable, PartialEq, Debug)]
+    enum Sample {
+        Unit,
+        Tuple(u8, i32),
+        Struct {
+            a: usize,
+            b: Option<String>,
+            c: Vec<(f32, bool)>,
+            d: i64,
+        },
+    }
+
+    fn samples() -> Vec<Sample> {
+        vec![
+            Sample::Unit,
+            Sample::Tuple(255, -1),
+            Sample::Struct {
+                a: 300,
+                b: Some("네모".to_string()),
+                c: vec![(1.5, true), (-0.25, false)],
+                d: i64::MIN,
+            },
+            Sample::Struct {
+                a: usize::MAX,
+                b: None,
+                c: vec![],
+                d: i64::MAX,
+            },
+        ]
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        for original in samples() {
+            let encoded = Codec::Binary.encode(&original).unwrap();
+            assert_eq!(encoded[0], BINARY_MAGIC);
+            assert_eq!(encoded[1], BINARY_VERSION);
+            let (decoded, codec): (Sample, _) = decode(&encoded).unwrap();
+            assert_eq!(codec, Codec::Binary);
+            assert_eq!(decoded, original);
+        }
+    }
+
+    #[test]
+    fn json_round_trip_ignores_trailing_newline() {
+        for original in samples() {
+            let mut encoded = Codec::Json.encode(&original).unwrap();
+            encoded.push(b'\n');
+            let (decoded, codec): (Sample, _) = decode(&encoded).unwrap();
+            assert_eq!(codec, Codec::Json);
+            assert_eq!(decoded, original);
+        }
+    }
+
+    #[test]
+    fn binary_is_smaller_than_json() {
+        // A bare unit variant is already tiny in JSON, and the frame header outweighs it.
+        for original in samples().into_iter().skip(1) {
+            let binary = Codec::Binary.encode(&original).unwrap();
+            let json = Codec::Json.encode(&original).unwrap();
+            assert!(binary.len() < json.len());
+        }
+    }
+
+    #[test]
+    fn binary_rejects_unknown_version() {
+        let mut encoded = Codec::Binary.encode(&Sample::Unit).unwrap();
+        encoded[1] = BINARY_VERSION + 1;
+        match Codec::Binary.decode::<Sample>(&encoded) {
+            Err(Error::UnsupportedVersion(v)) => assert_eq!(v, BINARY_VERSION + 1),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binary_rejects_truncated_frame() {
+        let encoded = Codec::Binary.encode(&samples()[2]).unwrap();
+        let truncated = &encoded[..encoded.len() - 1];
+        match Codec::Binary.decode::<Sample>(truncated) {
+            Err(Error::LengthMismatch { .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binary_rejects_unknown_variant() {
+        let mut encoded = Codec::Binary.encode(&Sample::Unit).unwrap();
+        let last = encoded.len() - 1;
+        encoded[last] = 7;
+        match Codec::Binary.decode::<Sample>(&encoded) {
+            Err(Error::InvalidVariant { index: 7, count: 3 }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -6,6 +6,12 @@ extern crate rustc_serialize;
 /// Codename of the project.
 pub const PROJECT_NAME: &str = "Project Fate";
 
+pub mod codec;
 pub mod manager;
 pub mod message;
+pub mod pending;
+pub mod replay;
 pub mod simple_logger;
+pub mod simulation;
+pub mod snapshot;
+pub mod transport;
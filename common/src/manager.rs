@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 pub type Id = usize;
 
@@ -14,7 +14,7 @@ where
     I: Item<P>,
 {
     next_id: AtomicUsize,
-    items: HashMap<Id, Rc<I>>,
+    items: HashMap<Id, Arc<I>>,
     _param_type: PhantomData<P>,
 }
 
@@ -30,18 +30,59 @@ where
         }
     }
 
-    pub fn create(&mut self, param: &P) -> Rc<I> {
+    pub fn create(&mut self, param: &P) -> Arc<I> {
         let id = self.next_id.fetch_add(1, Ordering::Relaxed);
         debug_assert!(!self.items.contains_key(&id));
 
-        let item = Rc::new(I::new(&id, param));
+        let item = Arc::new(I::new(&id, param));
         self.items.insert(id, item.clone());
         item
     }
 
-    pub fn get(&self, id: Id) -> Option<&Rc<I>> {
+    pub fn get(&self, id: Id) -> Option<&Arc<I>> {
         self.items.get(&id)
     }
+
+    pub fn remove(&mut self, id: Id) -> Option<Arc<I>> {
+        self.items.remove(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<I>> {
+        self.items.values()
+    }
+
+    /// Keeps only the items for which `f` returns `true`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&I) -> bool,
+    {
+        self.items.retain(|_, item| f(item))
+    }
+
+    /// Mutable access to an item. Returns `None` while someone still holds an `Arc` of it from
+    /// `create` or `get`, since they would not see the change.
+    pub fn get_mut(&mut self, id: Id) -> Option<&mut I> {
+        let item = Arc::get_mut(self.items.get_mut(&id)?);
+        debug_assert!(item.is_some(), "item id {} is shared", id);
+        item
+    }
+
+    /// Mutable access to every item that is not shared, see `get_mut`.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut I> {
+        self.items.iter_mut().filter_map(|(id, item)| {
+            let item = Arc::get_mut(item);
+            debug_assert!(item.is_some(), "item id {} is shared", id);
+            item
+        })
+    }
 }
 
 impl<I, P> Default for Manager<I, P>
@@ -54,6 +95,7 @@ where
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod test {
     use super::{Id, Item, Manager};
 
@@ -128,4 +170,108 @@ mod test {
         assert_eq!(item.a, 1);
         assert_eq!(item.b, true);
     }
+
+    #[test]
+    fn iter_method_visits_every_item() {
+        let mut manager = TestManager::new();
+        manager.create(&(1, true));
+        manager.create(&(2, false));
+
+        let mut ids: Vec<Id> = manager.iter().map(|item| item.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1]);
+    }
+
+    #[test]
+    fn iter_mut_method_changes_every_item() {
+        let mut manager = TestManager::new();
+        manager.create(&(1, true));
+        manager.create(&(2, false));
+
+        for item in manager.iter_mut() {
+            item.a *= 10;
+        }
+        let mut values: Vec<i32> = manager.iter().map(|item| item.a).collect();
+        values.sort();
+        assert_eq!(values, vec![10, 20]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "item id 0 is shared")]
+    fn get_mut_method_refuses_shared_item() {
+        let mut manager = TestManager::new();
+        let item = manager.create(&(1, true));
+
+        manager.get_mut(item.id);
+    }
+
+    #[test]
+    fn get_mut_method_changes_item() {
+        let mut manager = TestManager::new();
+        let id = manager.create(&(1, true)).id;
+
+        manager.get_mut(id).unwrap().a = 2;
+        assert_eq!(manager.get(id).unwrap().a, 2);
+    }
+
+    #[test]
+    fn remove_method_forgets_item() {
+        let mut manager = TestManager::new();
+        let first = manager.create(&(1, true)).id;
+        let second = manager.create(&(2, false)).id;
+        assert_eq!(manager.len(), 2);
+
+        let removed = manager.remove(first).unwrap();
+        assert_eq!(removed.id, first);
+        assert!(manager.get(first).is_none());
+        assert!(manager.remove(first).is_none());
+        assert_eq!(manager.len(), 1);
+
+        manager.remove(second);
+        assert!(manager.is_empty());
+    }
+
+    /// Ids of removed items must never be handed out again.
+    #[test]
+    fn create_method_does_not_reuse_removed_id() {
+        let mut manager = TestManager::new();
+        let id = manager.create(&(1, true)).id;
+        manager.remove(id);
+
+        let item = manager.create(&(1, true));
+        assert_ne!(item.id, id);
+    }
+
+    #[test]
+    fn retain_method_removes_rejected_items() {
+        let mut manager = TestManager::new();
+        manager.create(&(1, true));
+        manager.create(&(2, false));
+        manager.create(&(3, true));
+
+        manager.retain(|item| item.b);
+
+        let mut ids: Vec<Id> = manager.iter().map(|item| item.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0, 2]);
+    }
+
+    /// Managers are handed between threads.
+    #[test]
+    fn manager_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<TestManager>();
+    }
+
+    #[test]
+    fn get_mut_method_modifies_item() {
+        let mut manager = TestManager::new();
+
+        let id = manager.create(&(1, true)).id;
+        manager.get_mut(id).unwrap().a = 5;
+        assert_eq!(manager.get(id).unwrap().a, 5);
+
+        assert!(manager.get_mut(id + 1).is_none());
+    }
 }
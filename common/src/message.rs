@@ -1,46 +1,451 @@
 //! Messages between server and client
 
+use crate::codec;
+use crate::codec::Codec;
+use crate::simulation::{Position, Tick};
+use crate::snapshot::Snapshot;
 use rustc_serialize::json;
 use rustc_serialize::json::{DecodeResult, EncoderError};
 
+/// Version of the protocol spoken by this build. Peers decode variants by their position, so every
+/// release that changes a message bumps it once, appended variants included. Version 1 put it in
+/// the handshake, 2 added request ids, 3 session tokens, and 6 covers everything since, from the
+/// game lifecycle to spectating, in the variant order that stays. 4 and 5 never shipped.
+pub const PROTOCOL_VERSION: u32 = 6;
+/// Oldest protocol version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 6;
+
+/// Number of games in a `GameList` page, small enough for a page to fit in one datagram.
+pub const GAMES_PER_PAGE: usize = 6;
+
+/// How a finished game ended.
+#[derive(RustcDecodable, RustcEncodable, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum GameResult {
+    /// The other player left while the game was in progress.
+    Forfeit { winner_id: usize },
+}
+
+/// Where a game is in its lifecycle. Every change is pushed to its players with
+/// `GameStateChanged`.
+#[derive(RustcDecodable, RustcEncodable, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum GameState {
+    /// The host waits for a guest.
+    WaitingForPlayers,
+    /// Both players are in. The game starts once both sent `Ready`, or is abandoned if they take
+    /// longer than `seconds`.
+    ReadyCheck {
+        seconds: u32,
+    },
+    /// Both players are ready. The game starts in `seconds`.
+    Countdown {
+        seconds: u32,
+    },
+    /// The game is simulated and takes gameplay inputs.
+    InProgress,
+    Finished {
+        result: GameResult,
+    },
+    /// The game was closed without a result, by an admin, a server shutdown or a ready check
+    /// running out.
+    Abandoned,
+}
+
+/// A game as shown in the lobby.
+#[derive(RustcDecodable, RustcEncodable, PartialEq, Clone, Debug)]
+pub struct GameSummary {
+    pub game_id: usize,
+    pub host_id: usize,
+    pub players: usize,
+    pub state: GameState,
+}
+
+/// Why the server failed a request. Variants are encoded by their position, so new codes must
+/// only ever be appended.
+#[derive(RustcDecodable, RustcEncodable, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ErrorCode {
+    UnknownUser,
+    UnknownGame,
+    MalformedMessage,
+    NotAuthorized,
+    CapacityReached,
+    /// The request makes no sense, like joining one's own game.
+    InvalidRequest,
+    /// The game refused a gameplay input, like moving while using the Q skill.
+    InputRejected,
+    /// The server is shutting down and takes no new users or games.
+    ShuttingDown,
+    /// The request makes no sense in the state the game is in, like moving before it started.
+    WrongGameState,
+}
+
+/// Variants are encoded by their position, so new ones must only ever be appended.
+/// `ConnectResponse` and `ConnectRejected` must keep their position and fields in every version,
+/// so that any client can at least tell whether it got in.
 #[derive(RustcDecodable, RustcEncodable, PartialEq, Debug)]
 pub enum ServerToClient {
-    ConnectResponse { user_id: usize },
-    CreateGameResponse { game_id: usize },
+    /// `token` must accompany every later request acting as `user_id`, from the same address.
+    ConnectResponse {
+        user_id: usize,
+        token: SessionToken,
+    },
+    /// The server refused the handshake. It accepts `project` clients speaking a protocol version
+    /// in `min_version..=max_version`.
+    ConnectRejected {
+        project: String,
+        min_version: u32,
+        max_version: u32,
+    },
+    CreateGameResponse {
+        game_id: usize,
+    },
+    /// The request failed. `message` is meant for humans and may change at any time.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    JoinGameResponse {
+        game_id: usize,
+        host_id: usize,
+    },
+    /// Pushed to the host when a guest joins their game.
+    GuestJoined {
+        game_id: usize,
+        user_id: usize,
+    },
+    /// Page `page` of the games in the lobby, which are those waiting for a guest, ordered by id.
+    /// Pages are numbered from zero.
+    GameList {
+        page: usize,
+        page_count: usize,
+        games: Vec<GameSummary>,
+    },
+    LeaveGameResponse {
+        game_id: usize,
+    },
+    DisconnectResponse,
+    /// Pushed to the host when the guest leaves their game.
+    GuestLeft {
+        game_id: usize,
+        user_id: usize,
+    },
+    /// Pushed to the guest when the host leaves, making the guest the new host.
+    HostChanged {
+        game_id: usize,
+        host_id: usize,
+    },
+    Pong,
+    /// The state of a started game, pushed every tick on the unreliable channel. Clients should
+    /// answer with `SnapshotAck`, so that later snapshots only carry what changed.
+    Snapshot {
+        game_id: usize,
+        snapshot: Snapshot,
+    },
+    /// Pushed to every user when the server is stopped. Games in progress may go on for
+    /// `grace_seconds` at most, after which the server is gone.
+    ServerShuttingDown {
+        reason: String,
+        grace_seconds: u32,
+    },
+    /// A message from the operators, pushed to every user.
+    Announcement {
+        text: String,
+    },
+    /// The user waits for an opponent of a rating close to `rating`.
+    EnterQueueResponse {
+        rating: u32,
+    },
+    LeaveQueueResponse,
+    /// Pushed to both users the queue paired. They are in the game without joining, and it goes
+    /// through the ready check like any other.
+    MatchFound {
+        game_id: usize,
+        host_id: usize,
+        guest_id: usize,
+    },
+    /// Pushed to the players of a game whenever it moves on in its lifecycle.
+    GameStateChanged {
+        game_id: usize,
+        state: GameState,
+    },
+    /// The user now lives at the address the request came from, still playing in `game_ids`.
+    /// Each of them pushes its `GameStateChanged` again, then full snapshots.
+    ReconnectResponse {
+        user_id: usize,
+        game_ids: Vec<usize>,
+    },
+    /// The user watches the game. Its `GameStateChanged` follow, and once it is in progress its
+    /// snapshots, which show the world `delay_ticks` late.
+    SpectateResponse {
+        game_id: usize,
+        delay_ticks: Tick,
+    },
 }
 
+/// Variants are encoded by their position, so new ones must only ever be appended.
+/// `ConnectRequest` must stay the first variant with the same fields, so that `Handshake::peek`
+/// can read it whatever version sent it.
 #[derive(RustcDecodable, RustcEncodable, PartialEq, Debug)]
 pub enum ClientToServer {
-    ConnectRequest,
-    CreateGameRequest { user_id: usize },
+    ConnectRequest {
+        protocol_version: u32,
+        project: String,
+    },
+    CreateGameRequest {
+        user_id: usize,
+    },
+    JoinGameRequest {
+        user_id: usize,
+        game_id: usize,
+    },
+    ListGamesRequest {
+        page: usize,
+    },
+    LeaveGameRequest {
+        user_id: usize,
+        game_id: usize,
+    },
+    /// Leaves every game of the user and forgets about the user.
+    DisconnectRequest {
+        user_id: usize,
+    },
+    /// Keeps the user alive. The server evicts users it hasn't heard from in a while, so clients
+    /// send this whenever they have nothing else to say.
+    Ping {
+        user_id: usize,
+    },
+    /// The client holds the snapshot of `tick`. Not replied to unless it fails.
+    SnapshotAck {
+        user_id: usize,
+        game_id: usize,
+        tick: Tick,
+    },
+    /// Sends the user's Nemo to `dest`.
+    ///
+    /// Gameplay inputs carry the tick of the latest snapshot the client saw when the player acted.
+    /// They are not replied to unless rejected; their effect shows in the next snapshots.
+    MoveTo {
+        user_id: usize,
+        game_id: usize,
+        client_tick: Tick,
+        dest: Position,
+    },
+    /// Uses the skill in `slot`, where slot 0 is Q, aimed at `target`.
+    CastSkill {
+        user_id: usize,
+        game_id: usize,
+        client_tick: Tick,
+        slot: u8,
+        target: Position,
+    },
+    Stop {
+        user_id: usize,
+        game_id: usize,
+        client_tick: Tick,
+    },
+    /// Waits for the server to find an opponent, who is announced with `MatchFound`. Players of a
+    /// game can't queue, and creating or joining a game leaves the queue.
+    EnterQueue {
+        user_id: usize,
+    },
+    /// Stops waiting for an opponent.
+    LeaveQueue {
+        user_id: usize,
+    },
+    /// Confirms the user is ready to play, during the ready check. Not replied to unless it fails.
+    Ready {
+        user_id: usize,
+        game_id: usize,
+    },
+    /// Takes the user back from a new address, like after the network changed. Only the session
+    /// token is checked, and the server keeps users who went silent only for a while.
+    ReconnectRequest {
+        user_id: usize,
+    },
+    /// Watches a game without playing in it. Spectators get the snapshots the players get, maybe
+    /// delayed, and never have their inputs accepted. `LeaveGameRequest` stops watching.
+    SpectateRequest {
+        user_id: usize,
+        game_id: usize,
+    },
+}
+
+/// Chosen by the client to match responses to its requests.
+pub type RequestId = u32;
+
+/// Unguessable secret handed out in `ConnectResponse`, proving who sends a request.
+pub type SessionToken = String;
+
+/// What a client actually sends: a command tagged with an id the server echoes back. Commands on
+/// behalf of a user are only accepted with the `token` that user got on connect.
+#[derive(RustcDecodable, RustcEncodable, PartialEq, Debug)]
+pub struct Request {
+    pub id: RequestId,
+    pub token: Option<SessionToken>,
+    pub command: ClientToServer,
 }
 
-pub trait Message: Sized {
-    fn stringify(&self) -> Result<String, EncoderError>;
-    fn parse(_: &str) -> DecodeResult<Self>;
+/// What the server actually sends. `request_id` is the id of the request being answered, or
+/// `None` for messages the server pushes on its own, like `GuestJoined`.
+#[derive(RustcDecodable, RustcEncodable, PartialEq, Debug)]
+pub struct Response {
+    pub request_id: Option<RequestId>,
+    pub message: ServerToClient,
 }
 
-impl Message for ServerToClient {
-    fn stringify(&self) -> Result<String, EncoderError> {
-        json::encode(self)
+impl ClientToServer {
+    /// Handshake announcing the protocol version and the project this build speaks.
+    pub fn connect_request() -> Self {
+        ClientToServer::ConnectRequest {
+            protocol_version: PROTOCOL_VERSION,
+            project: crate::PROJECT_NAME.to_string(),
+        }
     }
-    fn parse(message: &str) -> DecodeResult<Self> {
-        json::decode(message)
+
+    /// Name of the variant, for logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ClientToServer::ConnectRequest { .. } => "ConnectRequest",
+            ClientToServer::CreateGameRequest { .. } => "CreateGameRequest",
+            ClientToServer::JoinGameRequest { .. } => "JoinGameRequest",
+            ClientToServer::ListGamesRequest { .. } => "ListGamesRequest",
+            ClientToServer::LeaveGameRequest { .. } => "LeaveGameRequest",
+            ClientToServer::DisconnectRequest { .. } => "DisconnectRequest",
+            ClientToServer::Ping { .. } => "Ping",
+            ClientToServer::SnapshotAck { .. } => "SnapshotAck",
+            ClientToServer::MoveTo { .. } => "MoveTo",
+            ClientToServer::CastSkill { .. } => "CastSkill",
+            ClientToServer::Stop { .. } => "Stop",
+            ClientToServer::EnterQueue { .. } => "EnterQueue",
+            ClientToServer::LeaveQueue { .. } => "LeaveQueue",
+            ClientToServer::Ready { .. } => "Ready",
+            ClientToServer::ReconnectRequest { .. } => "ReconnectRequest",
+            ClientToServer::SpectateRequest { .. } => "SpectateRequest",
+        }
     }
 }
 
-impl Message for ClientToServer {
+/// The connect handshake of any protocol version, as far as `Handshake::peek` can read it.
+#[derive(PartialEq, Debug)]
+pub struct Handshake {
+    /// Id of the request carrying the handshake, or `None` for versions before 2, which sent bare
+    /// commands and expect bare replies.
+    pub request_id: Option<RequestId>,
+    /// Zero for builds that predate the handshake and sent a plain `ConnectRequest`.
+    pub protocol_version: u32,
+    pub project: String,
+}
+
+/// How every version sends its connect handshake, newest first. They only decode the handshake,
+/// so a client of another version can be told which versions this build speaks.
+mod legacy {
+    use super::{RequestId, SessionToken};
+
+    /// The handshake since version 1, always the first variant of `ClientToServer`. Version 1
+    /// sent it bare.
+    #[derive(RustcDecodable, RustcEncodable)]
+    pub enum Versioned {
+        ConnectRequest {
+            protocol_version: u32,
+            project: String,
+        },
+    }
+
+    /// The handshake of builds from before version 1, which carried no version.
+    #[derive(RustcDecodable, RustcEncodable)]
+    pub enum Unversioned {
+        ConnectRequest,
+    }
+
+    /// Versions 3 to 6, which carry a session token.
+    #[derive(RustcDecodable, RustcEncodable)]
+    pub struct Request {
+        pub id: RequestId,
+        pub token: Option<SessionToken>,
+        pub command: Versioned,
+    }
+
+    /// Version 2, which wrapped commands for their request id, before session tokens.
+    #[derive(RustcDecodable, RustcEncodable)]
+    pub struct UntokenedRequest {
+        pub id: RequestId,
+        pub command: Versioned,
+    }
+}
+
+impl Handshake {
+    /// Reads the handshake of a datagram that may come from any protocol version. Returns `None`
+    /// if the datagram holds anything else.
+    pub fn peek(buf: &[u8]) -> Option<Handshake> {
+        use self::legacy::{Request, UntokenedRequest, Unversioned, Versioned};
+
+        let codec = Codec::detect(buf);
+        let handshake = |request_id, command| match command {
+            Versioned::ConnectRequest {
+                protocol_version,
+                project,
+            } => Handshake {
+                request_id,
+                protocol_version,
+                project,
+            },
+        };
+        if let Ok(request) = codec.decode::<Request>(buf) {
+            return Some(handshake(Some(request.id), request.command));
+        }
+        if let Ok(request) = codec.decode::<UntokenedRequest>(buf) {
+            return Some(handshake(Some(request.id), request.command));
+        }
+        if let Ok(command) = codec.decode::<Versioned>(buf) {
+            return Some(handshake(None, command));
+        }
+        match codec.decode::<Unversioned>(buf) {
+            Ok(Unversioned::ConnectRequest) => Some(Handshake {
+                request_id: None,
+                protocol_version: 0,
+                project: crate::PROJECT_NAME.to_string(),
+            }),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Whether a peer announcing the given handshake can talk to this build.
+pub fn is_compatible(protocol_version: u32, project: &str) -> bool {
+    project == crate::PROJECT_NAME
+        && (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version)
+}
+
+pub trait Message: rustc_serialize::Encodable + rustc_serialize::Decodable {
     fn stringify(&self) -> Result<String, EncoderError> {
         json::encode(self)
     }
     fn parse(message: &str) -> DecodeResult<Self> {
         json::decode(message)
     }
+    /// Encodes the message into a datagram with the given codec.
+    fn encode(&self, codec: Codec) -> codec::Result<Vec<u8>> {
+        codec.encode(self)
+    }
+    /// Decodes a datagram, also returning the codec it was encoded with.
+    fn decode(buf: &[u8]) -> codec::Result<(Self, Codec)> {
+        codec::decode(buf)
+    }
 }
 
+impl Message for ServerToClient {}
+
+impl Message for ClientToServer {}
+
+impl Message for Request {}
+
+impl Message for Response {}
+
 #[test]
 fn test_connect_response() {
-    let original = ServerToClient::ConnectResponse { user_id: 3 };
+    let original = ServerToClient::ConnectResponse {
+        user_id: 3,
+        token: "0123456789abcdef".to_string(),
+    };
     let encoded = original.stringify().unwrap();
     let parsed: ServerToClient = Message::parse(&encoded).unwrap();
     assert_eq!(parsed, original);
@@ -48,7 +453,7 @@ fn test_connect_response() {
 
 #[test]
 fn test_connect_request() {
-    let original = ClientToServer::ConnectRequest;
+    let original = ClientToServer::connect_request();
     let encoded = original.stringify().unwrap();
     let parsed: ClientToServer = Message::parse(&encoded).unwrap();
     assert_eq!(parsed, original);
@@ -69,3 +474,406 @@ fn test_create_game_response() {
     let parsed: ServerToClient = Message::parse(&encoded).unwrap();
     assert_eq!(parsed, original);
 }
+
+#[test]
+fn test_binary_round_trip() {
+    let original = ClientToServer::CreateGameRequest { user_id: 3 };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, codec) = ClientToServer::decode(&encoded).unwrap();
+    assert_eq!(codec, Codec::Binary);
+    assert_eq!(parsed, original);
+
+    let original = ServerToClient::ConnectResponse {
+        user_id: 3,
+        token: "0123456789abcdef".to_string(),
+    };
+    let encoded = original.encode(Codec::Json).unwrap();
+    let (parsed, codec) = ServerToClient::decode(&encoded).unwrap();
+    assert_eq!(codec, Codec::Json);
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_connect_rejected() {
+    let original = ServerToClient::ConnectRejected {
+        project: crate::PROJECT_NAME.to_string(),
+        min_version: MIN_PROTOCOL_VERSION,
+        max_version: PROTOCOL_VERSION,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_is_compatible() {
+    assert!(is_compatible(PROTOCOL_VERSION, crate::PROJECT_NAME));
+    assert!(!is_compatible(PROTOCOL_VERSION + 1, crate::PROJECT_NAME));
+    assert!(!is_compatible(
+        MIN_PROTOCOL_VERSION - 1,
+        crate::PROJECT_NAME
+    ));
+    assert!(!is_compatible(
+        PROTOCOL_VERSION,
+        "Project Unlimited Blade Works"
+    ));
+}
+
+#[test]
+fn test_peek_handshake() {
+    use self::legacy::{UntokenedRequest, Unversioned, Versioned};
+
+    let peek = |buf: &[u8]| {
+        let handshake = Handshake::peek(buf).unwrap();
+        (handshake.request_id, handshake.protocol_version)
+    };
+    let versioned = || Versioned::ConnectRequest {
+        protocol_version: 2,
+        project: crate::PROJECT_NAME.to_string(),
+    };
+
+    let current = Request {
+        id: 7,
+        token: None,
+        command: ClientToServer::connect_request(),
+    };
+    for &codec in &[Codec::Json, Codec::Binary] {
+        assert_eq!(
+            peek(&current.encode(codec).unwrap()),
+            (Some(7), PROTOCOL_VERSION)
+        );
+        let untokened = UntokenedRequest {
+            id: 8,
+            command: versioned(),
+        };
+        assert_eq!(peek(&codec.encode(&untokened).unwrap()), (Some(8), 2));
+        assert_eq!(peek(&codec.encode(&versioned()).unwrap()), (None, 2));
+        assert_eq!(
+            peek(&codec.encode(&Unversioned::ConnectRequest).unwrap()),
+            (None, 0)
+        );
+    }
+    assert_eq!(peek(b"\"ConnectRequest\"\n"), (None, 0));
+    assert_eq!(
+        peek(br#"{"variant":"ConnectRequest","fields":[1,"Project Fate"]}"#),
+        (None, 1)
+    );
+
+    let other = Request {
+        id: 9,
+        token: None,
+        command: ClientToServer::CreateGameRequest { user_id: 3 },
+    };
+    assert_eq!(Handshake::peek(&other.encode(Codec::Binary).unwrap()), None);
+    assert_eq!(Handshake::peek(b"\"Ping\""), None);
+    assert_eq!(Handshake::peek(b"garbage"), None);
+}
+
+#[test]
+fn test_error() {
+    let original = ServerToClient::Error {
+        code: ErrorCode::UnknownUser,
+        message: "user id 3 does not exist".to_string(),
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_join_game_request() {
+    let original = ClientToServer::JoinGameRequest {
+        user_id: 3,
+        game_id: 1,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ClientToServer = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_guest_joined() {
+    let original = ServerToClient::GuestJoined {
+        game_id: 1,
+        user_id: 3,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_game_list() {
+    let original = ServerToClient::GameList {
+        page: 0,
+        page_count: 1,
+        games: vec![GameSummary {
+            game_id: 1,
+            host_id: 3,
+            players: 1,
+            state: GameState::WaitingForPlayers,
+        }],
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+/// A full page must fit in a single datagram even with the verbose JSON codec. Only games waiting
+/// for players are listed, but a ready check leaves some room for more states.
+#[test]
+fn test_game_list_page_fits_in_datagram() {
+    let summary = GameSummary {
+        game_id: usize::MAX,
+        host_id: usize::MAX,
+        players: 2,
+        state: GameState::ReadyCheck { seconds: u32::MAX },
+    };
+    let original = ServerToClient::GameList {
+        page: usize::MAX,
+        page_count: usize::MAX,
+        games: vec![summary; GAMES_PER_PAGE],
+    };
+    assert!(original.encode(Codec::Json).unwrap().len() <= 1024);
+}
+
+#[test]
+fn test_leave_game_request() {
+    let original = ClientToServer::LeaveGameRequest {
+        user_id: 3,
+        game_id: 1,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ClientToServer = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_ping() {
+    let original = ClientToServer::Ping { user_id: 3 };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, _) = ClientToServer::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+
+    let original = ServerToClient::Pong;
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_snapshot() {
+    let world = crate::simulation::World::new(1, &[3, 4]);
+    let original = ServerToClient::Snapshot {
+        game_id: 1,
+        snapshot: Snapshot::full(world.tick(), world.units()),
+    };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, _) = ServerToClient::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+
+    let original = ClientToServer::SnapshotAck {
+        user_id: 3,
+        game_id: 1,
+        tick: 20,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ClientToServer = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_server_shutting_down() {
+    let original = ServerToClient::ServerShuttingDown {
+        reason: "maintenance".to_string(),
+        grace_seconds: 30,
+    };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, _) = ServerToClient::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_announcement() {
+    let original = ServerToClient::Announcement {
+        text: "restarting in 5 minutes".to_string(),
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_queue() {
+    let original = ClientToServer::EnterQueue { user_id: 3 };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, _) = ClientToServer::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+
+    let original = ServerToClient::MatchFound {
+        game_id: 1,
+        host_id: 3,
+        guest_id: 4,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_game_state_changed() {
+    let states = vec![
+        GameState::ReadyCheck { seconds: 30 },
+        GameState::Finished {
+            result: GameResult::Forfeit { winner_id: 3 },
+        },
+        GameState::Abandoned,
+    ];
+    for state in states {
+        let original = ServerToClient::GameStateChanged { game_id: 1, state };
+        let encoded = original.encode(Codec::Binary).unwrap();
+        let (parsed, _) = ServerToClient::decode(&encoded).unwrap();
+        assert_eq!(parsed, original);
+
+        let encoded = original.stringify().unwrap();
+        let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+        assert_eq!(parsed, original);
+    }
+
+    let original = ClientToServer::Ready {
+        user_id: 3,
+        game_id: 1,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ClientToServer = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_reconnect() {
+    let original = ClientToServer::ReconnectRequest { user_id: 3 };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, _) = ClientToServer::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+
+    let original = ServerToClient::ReconnectResponse {
+        user_id: 3,
+        game_ids: vec![1, 4],
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_spectate() {
+    let original = ClientToServer::SpectateRequest {
+        user_id: 3,
+        game_id: 1,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ClientToServer = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+
+    let original = ServerToClient::SpectateResponse {
+        game_id: 1,
+        delay_ticks: 200,
+    };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, _) = ServerToClient::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_gameplay_inputs() {
+    let originals = vec![
+        ClientToServer::MoveTo {
+            user_id: 3,
+            game_id: 1,
+            client_tick: 40,
+            dest: (-12.5, 3.0),
+        },
+        ClientToServer::CastSkill {
+            user_id: 3,
+            game_id: 1,
+            client_tick: 41,
+            slot: 0,
+            target: (0.0, 0.0),
+        },
+        ClientToServer::Stop {
+            user_id: 3,
+            game_id: 1,
+            client_tick: 42,
+        },
+    ];
+    for original in originals {
+        let encoded = original.encode(Codec::Binary).unwrap();
+        let (parsed, _) = ClientToServer::decode(&encoded).unwrap();
+        assert_eq!(parsed, original);
+
+        let encoded = original.stringify().unwrap();
+        let parsed: ClientToServer = Message::parse(&encoded).unwrap();
+        assert_eq!(parsed, original);
+    }
+}
+
+#[test]
+fn test_host_changed() {
+    let original = ServerToClient::HostChanged {
+        game_id: 1,
+        host_id: 3,
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: ServerToClient = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_request() {
+    let original = Request {
+        id: 7,
+        token: None,
+        command: ClientToServer::connect_request(),
+    };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, _) = Request::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+
+    let original = Request {
+        id: 8,
+        token: Some("0123456789abcdef".to_string()),
+        command: ClientToServer::CreateGameRequest { user_id: 3 },
+    };
+    let encoded = original.encode(Codec::Json).unwrap();
+    let (parsed, _) = Request::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
+
+#[test]
+fn test_response() {
+    let original = Response {
+        request_id: Some(7),
+        message: ServerToClient::ConnectResponse {
+            user_id: 3,
+            token: "0123456789abcdef".to_string(),
+        },
+    };
+    let encoded = original.stringify().unwrap();
+    let parsed: Response = Message::parse(&encoded).unwrap();
+    assert_eq!(parsed, original);
+
+    let original = Response {
+        request_id: None,
+        message: ServerToClient::GuestJoined {
+            game_id: 1,
+            user_id: 3,
+        },
+    };
+    let encoded = original.encode(Codec::Binary).unwrap();
+    let (parsed, _) = Response::decode(&encoded).unwrap();
+    assert_eq!(parsed, original);
+}
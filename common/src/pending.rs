@@ -0,0 +1,123 @@
+//! Bookkeeping of requests waiting for their response.
+
+use crate::message::RequestId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Entry<T> {
+    context: T,
+    deadline: Instant,
+}
+
+/// Hands out request ids and matches responses against them. Each request carries a `context`
+/// of the caller's choice, given back when the request completes or expires.
+pub struct PendingRequests<T> {
+    next_id: RequestId,
+    timeout: Duration,
+    pending: HashMap<RequestId, Entry<T>>,
+}
+
+impl<T> PendingRequests<T> {
+    pub fn new(timeout: Duration) -> Self {
+        PendingRequests {
+            next_id: 0,
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Registers a new request, returning the id to send it with.
+    pub fn start(&mut self, context: T, now: Instant) -> RequestId {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending.insert(
+            id,
+            Entry {
+                context,
+                deadline: now + self.timeout,
+            },
+        );
+        id
+    }
+
+    /// Matches a response. Returns `None` if no such request is pending, either because the id is
+    /// bogus or because the request already completed or expired.
+    pub fn complete(&mut self, request_id: RequestId) -> Option<T> {
+        self.pending.remove(&request_id).map(|entry| entry.context)
+    }
+
+    /// Removes and returns the requests that got no response in time.
+    pub fn expire(&mut self, now: Instant) -> Vec<(RequestId, T)> {
+        let expired: Vec<RequestId> = self
+            .pending
+            .iter()
+            .filter(|&(_, entry)| entry.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| self.pending.remove(&id).map(|entry| (id, entry.context)))
+            .collect()
+    }
+
+    /// When the earliest pending request expires.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|entry| entry.deadline).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PendingRequests;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn start_hands_out_distinct_ids() {
+        let now = Instant::now();
+        let mut pending = PendingRequests::new(Duration::from_secs(1));
+
+        let a = pending.start("a", now);
+        let b = pending.start("b", now);
+        assert_ne!(a, b);
+        assert_eq!(pending.len(), 2);
+    }
+
+    #[test]
+    fn complete_matches_each_response_once() {
+        let now = Instant::now();
+        let mut pending = PendingRequests::new(Duration::from_secs(1));
+
+        let a = pending.start("a", now);
+        let b = pending.start("b", now);
+        assert_eq!(pending.complete(b), Some("b"));
+        assert_eq!(pending.complete(b), None);
+        assert_eq!(pending.complete(a), Some("a"));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn expire_returns_overdue_requests_only() {
+        let now = Instant::now();
+        let mut pending = PendingRequests::new(Duration::from_secs(1));
+
+        let early = pending.start("early", now);
+        let late = pending.start("late", now + Duration::from_millis(500));
+        assert_eq!(pending.next_deadline(), Some(now + Duration::from_secs(1)));
+
+        assert!(pending.expire(now).is_empty());
+        assert_eq!(
+            pending.expire(now + Duration::from_secs(1)),
+            vec![(early, "early")]
+        );
+        assert_eq!(pending.complete(early), None);
+        assert_eq!(pending.complete(late), Some("late"));
+    }
+}
@@ -0,0 +1,256 @@
+//! Match recordings, to replay a disputed game after the fact.
+//!
+//! The simulation is deterministic, so a match is fully described by its seed, its players and
+//! the inputs accepted at each tick. A replay also keeps the initial and final units, which lets
+//! `verify` check that re-simulating the inputs reproduces what the server saw. On disk it looks
+//! like below, the replay itself in the binary codec.
+//!
+//! ```text
+//! +-----------------+------------------+-----------------------+
+//! | magic "FRPL"    | version (u32le)  | replay (binary frame) |
+//! +-----------------+------------------+-----------------------+
+//! ```
+
+use crate::codec::{self, Codec};
+use crate::simulation::{Input, Rejection, Tick, Unit, World};
+
+pub const MAGIC: &[u8; 4] = b"FRPL";
+/// Version of the replay layout. Bump it whenever `Replay` or the simulation rules change, since
+/// older replays would not reproduce anymore.
+pub const REPLAY_VERSION: u32 = 1;
+const HEADER_LEN: usize = 8;
+
+/// An input some player gave while the world was at `tick`. It took effect from the next tick on.
+#[derive(RustcDecodable, RustcEncodable, Clone, PartialEq, Debug)]
+pub struct RecordedInput {
+    pub tick: Tick,
+    pub player: usize,
+    pub input: Input,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, PartialEq, Debug)]
+pub struct Replay {
+    pub game_id: usize,
+    pub seed: u64,
+    pub players: Vec<usize>,
+    pub initial: Vec<Unit>,
+    /// Accepted inputs, in the order they were applied.
+    pub inputs: Vec<RecordedInput>,
+    /// Tick the recording stopped at, and the units then.
+    pub end_tick: Tick,
+    pub end: Vec<Unit>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The file does not start with `MAGIC`.
+    NotAReplay,
+    UnsupportedVersion(u32),
+    Codec(codec::Error),
+}
+
+impl From<codec::Error> for Error {
+    fn from(err: codec::Error) -> Self {
+        Error::Codec(err)
+    }
+}
+
+/// Where re-simulating a replay went differently than recorded.
+#[derive(PartialEq, Debug)]
+pub enum Divergence {
+    /// The seed and players spawn different units.
+    Initial,
+    /// A recorded input was refused, though the server accepted it.
+    Rejected { index: usize, rejection: Rejection },
+    /// An input is recorded at a tick the recording never reached, or out of order.
+    InputOutOfRange { index: usize },
+    /// The units at `end_tick` are not in the same places or states.
+    End,
+}
+
+/// Whether two sets of units ended up the same. Angles come from `atan2`, which libm computes
+/// differently from one platform to another, so they are left out. They only face the units where
+/// they move, which positions and states already capture.
+fn same_end(left: &[Unit], right: &[Unit]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .all(|(l, r)| l.id == r.id && l.kind == r.kind && l.pos == r.pos && l.state == r.state)
+}
+
+impl Replay {
+    /// Starts recording a match, whose world was just created.
+    pub fn new(game_id: usize, seed: u64, players: &[usize], world: &World) -> Self {
+        Replay {
+            game_id,
+            seed,
+            players: players.to_vec(),
+            initial: world.units().to_vec(),
+            inputs: Vec::new(),
+            end_tick: world.tick(),
+            end: world.units().to_vec(),
+        }
+    }
+
+    /// Records an input the world accepted at its current tick.
+    pub fn record(&mut self, tick: Tick, player: usize, input: Input) {
+        self.inputs.push(RecordedInput {
+            tick,
+            player,
+            input,
+        });
+    }
+
+    /// Records the state the match ended in.
+    pub fn finish(&mut self, world: &World) {
+        self.end_tick = world.tick();
+        self.end = world.units().to_vec();
+    }
+
+    /// Re-simulates the match, checking that it ends where the recording says.
+    pub fn verify(&self) -> Result<World, Divergence> {
+        let mut world = World::new(self.seed, &self.players);
+        if world.units() != &self.initial[..] {
+            return Err(Divergence::Initial);
+        }
+
+        let mut inputs = self.inputs.iter().enumerate().peekable();
+        loop {
+            while let Some((index, recorded)) = inputs.next_if(|(_, r)| r.tick == world.tick()) {
+                world
+                    .apply(recorded.player, recorded.input)
+                    .map_err(|rejection| Divergence::Rejected { index, rejection })?;
+            }
+            if let Some(&(index, _)) = inputs.peek() {
+                if world.tick() >= self.end_tick {
+                    return Err(Divergence::InputOutOfRange { index });
+                }
+            }
+            if world.tick() >= self.end_tick {
+                break;
+            }
+            world.step();
+        }
+
+        if !same_end(world.units(), &self.end) {
+            return Err(Divergence::End);
+        }
+        Ok(world)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::with_capacity(HEADER_LEN);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&REPLAY_VERSION.to_le_bytes());
+        buf.extend_from_slice(&Codec::Binary.encode(self)?);
+        Ok(buf)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < HEADER_LEN || &buf[..4] != MAGIC {
+            return Err(Error::NotAReplay);
+        }
+        let version = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if version != REPLAY_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        Ok(Codec::Binary.decode(&buf[HEADER_LEN..])?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Divergence, Error, RecordedInput, Replay};
+    use crate::simulation::{Input, World};
+
+    /// Plays a short match, recording it like the server does.
+    fn record() -> Replay {
+        let mut world = World::new(7, &[1, 2]);
+        let mut replay = Replay::new(3, 7, &[1, 2], &world);
+        for tick in 0..60 {
+            let input = match tick {
+                5 => Some((1, Input::MoveTo { dest: (20.0, 5.0) })),
+                12 => Some((
+                    2,
+                    Input::CastSkill {
+                        slot: 0,
+                        target: (0.0, 0.0),
+                    },
+                )),
+                // Refused, Q lasts longer than this, so it must not be recorded.
+                20 => Some((2, Input::Stop)),
+                40 => Some((1, Input::Stop)),
+                _ => None,
+            };
+            if let Some((player, input)) = input {
+                if world.apply(player, input).is_ok() {
+                    replay.record(world.tick(), player, input);
+                }
+            }
+            world.step();
+        }
+        replay.finish(&world);
+        replay
+    }
+
+    #[test]
+    fn recorded_match_reproduces() {
+        let replay = record();
+        assert_eq!(replay.inputs.len(), 3);
+        assert_eq!(replay.verify().unwrap().tick(), 60);
+    }
+
+    #[test]
+    fn tampered_inputs_diverge() {
+        let mut replay = record();
+        replay.inputs[0].input = Input::MoveTo { dest: (20.0, 6.0) };
+        assert_eq!(replay.verify().unwrap_err(), Divergence::End);
+
+        let mut replay = record();
+        let stop = RecordedInput {
+            tick: 13,
+            player: 2,
+            input: Input::Stop,
+        };
+        replay.inputs.insert(2, stop);
+        match replay.verify() {
+            Err(Divergence::Rejected { index: 2, .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut replay = record();
+        replay.seed = 8;
+        assert_eq!(replay.verify().unwrap_err(), Divergence::End);
+    }
+
+    #[test]
+    fn end_angles_are_not_compared() {
+        let mut replay = record();
+        for unit in &mut replay.end {
+            unit.angle += 0.001;
+        }
+        assert!(replay.verify().is_ok());
+
+        replay.end[0].pos.0 += 0.001;
+        assert_eq!(replay.verify().unwrap_err(), Divergence::End);
+    }
+
+    #[test]
+    fn bytes_roundtrip() {
+        let replay = record();
+        let bytes = replay.to_bytes().unwrap();
+        assert_eq!(Replay::from_bytes(&bytes).unwrap(), replay);
+
+        match Replay::from_bytes(&bytes[1..]) {
+            Err(Error::NotAReplay) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut newer = bytes;
+        newer[4] = 2;
+        match Replay::from_bytes(&newer) {
+            Err(Error::UnsupportedVersion(2)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -21,7 +21,12 @@ impl Log for SimpleLogger {
 }
 
 pub fn init() -> Result<(), SetLoggerError> {
+    init_with_level(LevelFilter::Info)
+}
+
+/// Like `init`, but only records at or above `level` are printed.
+pub fn init_with_level(level: LevelFilter) -> Result<(), SetLoggerError> {
     set_logger(&SimpleLogger)?;
-    set_max_level(LevelFilter::Info);
+    set_max_level(level);
     Ok(())
 }
@@ -0,0 +1,384 @@
+//! Deterministic game simulation, run by the server as the authority over every unit.
+//!
+//! The rules mirror what the client used to do on its own: Nemo walks at `SPEED` towards where it
+//! was sent, its Q skill lasts `Q_SKILL_TICKS` and cannot be interrupted, and minions rest for
+//! `MINION_REST_TICKS` before wandering to a random spot. Time advances in fixed ticks, and randomness
+//! comes from a seeded generator, so the same seed and inputs always give the same world.
+//! Positions only use `+`, `-`, `*`, `/` and `sqrt`, which IEEE 754 rounds exactly everywhere.
+
+use std::time::Duration;
+
+pub type Tick = u32;
+pub type UnitId = usize;
+pub type Position = (f32, f32);
+
+pub const TICKS_PER_SECOND: u32 = 20;
+pub const TICK_INTERVAL: Duration = Duration::from_millis(1000 / TICKS_PER_SECOND as u64);
+/// Simulated seconds per tick.
+const ELAPSED: f32 = 1.0 / TICKS_PER_SECOND as f32;
+
+/// Distance a unit walks per second.
+pub const SPEED: f32 = 50.0;
+/// Ticks the Q skill lasts, one second.
+pub const Q_SKILL_TICKS: Tick = TICKS_PER_SECOND;
+/// Ticks a minion rests before wandering again, one and a half seconds.
+pub const MINION_REST_TICKS: Tick = TICKS_PER_SECOND * 3 / 2;
+/// Minions wander to random spots in `-MINION_RANGE..MINION_RANGE` on both axes.
+pub const MINION_RANGE: f32 = 10.0;
+/// Half width and half height of the arena, which is what the client's 1024x768 window shows at
+/// 10 pixels per unit.
+pub const ARENA: (f32, f32) = (51.2, 38.4);
+
+const NEMO_SPAWNS: [Position; 2] = [(-5.0, 0.0), (5.0, 0.0)];
+const MINION_SPAWNS: [Position; 10] = [
+    (-17.0, 4.0),
+    (-19.0, 2.0),
+    (-20.0, 0.0),
+    (-19.0, -2.0),
+    (-17.0, -4.0),
+    (17.0, 4.0),
+    (19.0, 2.0),
+    (20.0, 0.0),
+    (19.0, -2.0),
+    (17.0, -4.0),
+];
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Copy, PartialEq, Debug)]
+pub enum UnitKind {
+    /// The unit a player controls.
+    Nemo {
+        owner: usize,
+    },
+    Minion,
+}
+
+/// What a unit is doing. Timed states remember the tick they started at rather than counting, so
+/// that a unit doing the same thing stays equal from tick to tick.
+#[derive(RustcDecodable, RustcEncodable, Clone, Copy, PartialEq, Debug)]
+pub enum UnitState {
+    /// Standing still since tick `since`.
+    Stopped {
+        since: Tick,
+    },
+    Moving {
+        dest: Position,
+    },
+    /// Using Q since tick `since`.
+    QSkill {
+        since: Tick,
+    },
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, PartialEq, Debug)]
+pub struct Unit {
+    pub id: UnitId,
+    pub kind: UnitKind,
+    pub pos: Position,
+    pub angle: f32,
+    pub state: UnitState,
+}
+
+impl Unit {
+    fn go(&mut self, dest: Position) {
+        if self.pos == dest {
+            return;
+        }
+        let dx = dest.0 - self.pos.0;
+        let dy = dest.1 - self.pos.1;
+        self.angle = dy.atan2(dx);
+        self.state = UnitState::Moving { dest };
+    }
+
+    /// Moves the unit on to tick `tick`.
+    fn update(&mut self, tick: Tick) {
+        let next = match self.state {
+            UnitState::Stopped { .. } => None,
+            UnitState::Moving { dest } => {
+                let dx = dest.0 - self.pos.0;
+                let dy = dest.1 - self.pos.1;
+                let left_dist = (dx * dx + dy * dy).sqrt();
+                let diff = SPEED * ELAPSED;
+
+                if left_dist <= diff {
+                    self.pos = dest;
+                    Some(UnitState::Stopped { since: tick })
+                } else {
+                    self.pos.0 += diff * dx / left_dist;
+                    self.pos.1 += diff * dy / left_dist;
+                    None
+                }
+            }
+            UnitState::QSkill { since } if Q_SKILL_TICKS <= tick - since => {
+                Some(UnitState::Stopped { since: tick })
+            }
+            UnitState::QSkill { .. } => None,
+        };
+
+        if let Some(next) = next {
+            self.state = next;
+        }
+    }
+}
+
+/// What a player asks its Nemo to do.
+#[derive(RustcDecodable, RustcEncodable, Clone, Copy, PartialEq, Debug)]
+pub enum Input {
+    MoveTo {
+        dest: Position,
+    },
+    /// Only slot 0, the Q skill, exists. It is not targeted, so `target` is ignored for now.
+    CastSkill {
+        slot: u8,
+        target: Position,
+    },
+    Stop,
+}
+
+/// Why an `Input` was refused.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rejection {
+    /// The player has no Nemo in this world.
+    UnknownPlayer,
+    UnknownSkill,
+    /// The destination is not a finite point inside the `ARENA`.
+    OutOfArena,
+    /// Nemo is busy with its Q skill, which cannot be interrupted.
+    Casting,
+}
+
+/// SplitMix64. Unlike `rand`, its output is fixed forever, which replaying a seed relies on.
+#[derive(Clone, Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `low..high`.
+    fn range(&mut self, low: f32, high: f32) -> f32 {
+        let unit = (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32;
+        low + (high - low) * unit
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct World {
+    tick: Tick,
+    units: Vec<Unit>,
+    rng: Rng,
+}
+
+fn in_arena(pos: Position) -> bool {
+    pos.0.abs() <= ARENA.0 && pos.1.abs() <= ARENA.1
+}
+
+impl World {
+    /// Spawns a Nemo for each of the (at most two) `players` and the minions.
+    pub fn new(seed: u64, players: &[usize]) -> Self {
+        let nemos = players
+            .iter()
+            .zip(NEMO_SPAWNS.iter())
+            .map(|(&owner, &pos)| (UnitKind::Nemo { owner }, pos));
+        let minions = MINION_SPAWNS.iter().map(|&pos| (UnitKind::Minion, pos));
+        let units = nemos
+            .chain(minions)
+            .enumerate()
+            .map(|(id, (kind, pos))| Unit {
+                id,
+                kind,
+                pos,
+                angle: 0.0,
+                state: UnitState::Stopped { since: 0 },
+            })
+            .collect();
+
+        World {
+            tick: 0,
+            units,
+            rng: Rng(seed),
+        }
+    }
+
+    /// Number of ticks simulated so far.
+    pub fn tick(&self) -> Tick {
+        self.tick
+    }
+
+    pub fn units(&self) -> &[Unit] {
+        &self.units
+    }
+
+    /// The Nemo of the player.
+    pub fn nemo(&self, player: usize) -> Option<&Unit> {
+        self.units
+            .iter()
+            .find(|unit| unit.kind == UnitKind::Nemo { owner: player })
+    }
+
+    /// Applies the input of a player, taking effect from the next tick on.
+    pub fn apply(&mut self, player: usize, input: Input) -> Result<(), Rejection> {
+        let nemo = self
+            .units
+            .iter_mut()
+            .find(|unit| unit.kind == UnitKind::Nemo { owner: player })
+            .ok_or(Rejection::UnknownPlayer)?;
+        if let UnitState::QSkill { .. } = nemo.state {
+            return Err(Rejection::Casting);
+        }
+
+        let tick = self.tick;
+        match input {
+            Input::MoveTo { dest } => {
+                if !in_arena(dest) {
+                    return Err(Rejection::OutOfArena);
+                }
+                nemo.go(dest);
+            }
+            Input::CastSkill { slot: 0, .. } => nemo.state = UnitState::QSkill { since: tick },
+            Input::CastSkill { .. } => return Err(Rejection::UnknownSkill),
+            Input::Stop => nemo.state = UnitState::Stopped { since: tick },
+        }
+        Ok(())
+    }
+
+    /// Advances the world by one tick.
+    pub fn step(&mut self) {
+        let tick = self.tick;
+        for unit in &mut self.units {
+            match (unit.kind, unit.state) {
+                (UnitKind::Minion, UnitState::Stopped { since })
+                    if MINION_REST_TICKS <= tick - since =>
+                {
+                    let x = self.rng.range(-MINION_RANGE, MINION_RANGE);
+                    let y = self.rng.range(-MINION_RANGE, MINION_RANGE);
+                    unit.go((x, y));
+                }
+                _ => {}
+            }
+            unit.update(tick + 1);
+        }
+        self.tick += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Input, Rejection, UnitKind, UnitState, World, MINION_RANGE, TICKS_PER_SECOND};
+
+    fn steps(world: &mut World, ticks: u32) {
+        for _ in 0..ticks {
+            world.step();
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_world() {
+        let mut a = World::new(42, &[1, 2]);
+        let mut b = World::new(42, &[1, 2]);
+        a.apply(1, Input::MoveTo { dest: (30.0, 7.0) }).unwrap();
+        b.apply(1, Input::MoveTo { dest: (30.0, 7.0) }).unwrap();
+        steps(&mut a, 10 * TICKS_PER_SECOND);
+        steps(&mut b, 10 * TICKS_PER_SECOND);
+        assert_eq!(a.units(), b.units());
+        assert_eq!(a.tick(), 10 * TICKS_PER_SECOND);
+
+        let mut c = World::new(43, &[1, 2]);
+        steps(&mut c, 10 * TICKS_PER_SECOND);
+        assert!(a.units()[2..] != c.units()[2..]);
+    }
+
+    #[test]
+    fn nemo_walks_at_speed_and_stops_on_arrival() {
+        let mut world = World::new(0, &[1]);
+        let start = world.nemo(1).unwrap().pos;
+        world
+            .apply(
+                1,
+                Input::MoveTo {
+                    dest: (start.0 + 25.0, start.1),
+                },
+            )
+            .unwrap();
+
+        steps(&mut world, TICKS_PER_SECOND / 4);
+        let nemo = world.nemo(1).unwrap();
+        assert!((nemo.pos.0 - (start.0 + 12.5)).abs() < 1e-3);
+        assert_eq!(nemo.angle, 0.0);
+
+        steps(&mut world, TICKS_PER_SECOND / 4);
+        let nemo = world.nemo(1).unwrap();
+        assert_eq!(nemo.pos, (start.0 + 25.0, start.1));
+        match nemo.state {
+            UnitState::Stopped { .. } => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn q_skill_cannot_be_interrupted() {
+        let mut world = World::new(0, &[1]);
+        let q = Input::CastSkill {
+            slot: 0,
+            target: (0.0, 0.0),
+        };
+        world.apply(1, q).unwrap();
+        assert_eq!(
+            world.apply(1, Input::MoveTo { dest: (1.0, 1.0) }),
+            Err(Rejection::Casting)
+        );
+        assert_eq!(world.apply(1, Input::Stop), Err(Rejection::Casting));
+
+        steps(&mut world, TICKS_PER_SECOND);
+        world.apply(1, Input::MoveTo { dest: (1.0, 1.0) }).unwrap();
+    }
+
+    #[test]
+    fn invalid_inputs_are_rejected() {
+        let mut world = World::new(0, &[1]);
+        assert_eq!(world.apply(2, Input::Stop), Err(Rejection::UnknownPlayer));
+        assert_eq!(
+            world.apply(
+                1,
+                Input::MoveTo {
+                    dest: (1000.0, 0.0)
+                }
+            ),
+            Err(Rejection::OutOfArena)
+        );
+        assert_eq!(
+            world.apply(
+                1,
+                Input::MoveTo {
+                    dest: (f32::NAN, 0.0)
+                }
+            ),
+            Err(Rejection::OutOfArena)
+        );
+        let w = Input::CastSkill {
+            slot: 1,
+            target: (0.0, 0.0),
+        };
+        assert_eq!(world.apply(1, w), Err(Rejection::UnknownSkill));
+    }
+
+    #[test]
+    fn minions_wander_within_range() {
+        let mut world = World::new(7, &[1, 2]);
+        steps(&mut world, 30 * TICKS_PER_SECOND);
+        for unit in world.units() {
+            if unit.kind != UnitKind::Minion {
+                continue;
+            }
+            if let UnitState::Moving { dest } = unit.state {
+                assert!(dest.0.abs() <= MINION_RANGE && dest.1.abs() <= MINION_RANGE);
+            }
+            // By now every minion reached at least one random spot.
+            assert!(unit.pos.0.abs() <= MINION_RANGE && unit.pos.1.abs() <= MINION_RANGE);
+        }
+    }
+}
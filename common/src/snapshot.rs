@@ -0,0 +1,229 @@
+//! Delta compressed world states.
+//!
+//! The server keeps the last snapshots it sent to each client in a `SnapshotSender`. Each new one
+//! only carries the units that changed since the latest snapshot the client acknowledged, or every
+//! unit when there is no such baseline. The client keeps what it received in a `SnapshotReceiver`
+//! to apply deltas to the right baseline, since snapshots travel unreliably and may be lost or
+//! reordered.
+
+use crate::simulation::{Tick, Unit, UnitId};
+use std::collections::VecDeque;
+
+/// How many snapshots are remembered on each end. A client acknowledging an older one gets a full
+/// snapshot next.
+pub const HISTORY_LEN: usize = 32;
+
+/// The units of a world at `tick`. Without a `baseline`, `units` are all units there are.
+/// Otherwise `units` are the ones that changed or appeared since the snapshot of tick `baseline`,
+/// and `removed` the ones that disappeared.
+#[derive(RustcDecodable, RustcEncodable, Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    pub tick: Tick,
+    pub baseline: Option<Tick>,
+    pub units: Vec<Unit>,
+    pub removed: Vec<UnitId>,
+}
+
+impl Snapshot {
+    /// Snapshot of `units` relative to `baseline`.
+    pub fn delta(tick: Tick, baseline: (Tick, &[Unit]), units: &[Unit]) -> Self {
+        let (baseline_tick, baseline) = baseline;
+        let changed = units
+            .iter()
+            .filter(|unit| !baseline.contains(unit))
+            .cloned()
+            .collect();
+        let removed = baseline
+            .iter()
+            .filter(|old| !units.iter().any(|unit| unit.id == old.id))
+            .map(|old| old.id)
+            .collect();
+        Snapshot {
+            tick,
+            baseline: Some(baseline_tick),
+            units: changed,
+            removed,
+        }
+    }
+
+    pub fn full(tick: Tick, units: &[Unit]) -> Self {
+        Snapshot {
+            tick,
+            baseline: None,
+            units: units.to_vec(),
+            removed: Vec::new(),
+        }
+    }
+
+    /// The complete units this snapshot describes, given the units of its baseline.
+    pub fn apply(&self, baseline: &[Unit]) -> Vec<Unit> {
+        let mut units: Vec<Unit> = baseline
+            .iter()
+            .filter(|old| !self.removed.contains(&old.id))
+            .filter(|old| !self.units.iter().any(|unit| unit.id == old.id))
+            .cloned()
+            .collect();
+        units.extend(self.units.iter().cloned());
+        units.sort_by_key(|unit| unit.id);
+        units
+    }
+}
+
+/// Server side: what was sent to one client.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotSender {
+    sent: VecDeque<(Tick, Vec<Unit>)>,
+    acked: Option<Tick>,
+}
+
+impl SnapshotSender {
+    pub fn new() -> Self {
+        SnapshotSender::default()
+    }
+
+    /// The snapshot to send for `units` at `tick`, remembered as a future baseline.
+    pub fn snapshot(&mut self, tick: Tick, units: &[Unit]) -> Snapshot {
+        let baseline = self.acked.and_then(|acked| {
+            self.sent
+                .iter()
+                .find(|&&(sent, _)| sent == acked)
+                .map(|(sent, units)| (*sent, &units[..]))
+        });
+        let snapshot = match baseline {
+            Some(baseline) => Snapshot::delta(tick, baseline, units),
+            None => Snapshot::full(tick, units),
+        };
+
+        if self.sent.len() == HISTORY_LEN {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((tick, units.to_vec()));
+        snapshot
+    }
+
+    /// The client received the snapshot of `tick`. Older acks are ignored.
+    pub fn ack(&mut self, tick: Tick) {
+        match self.acked {
+            Some(acked) if tick <= acked => {}
+            _ => self.acked = Some(tick),
+        }
+    }
+}
+
+/// Client side: the complete states received so far.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotReceiver {
+    received: VecDeque<(Tick, Vec<Unit>)>,
+}
+
+impl SnapshotReceiver {
+    pub fn new() -> Self {
+        SnapshotReceiver::default()
+    }
+
+    /// Rebuilds the complete units of the snapshot. Returns `None` if the snapshot is older than
+    /// the latest one, or if its baseline is unknown. Otherwise its tick should be acknowledged.
+    pub fn receive(&mut self, snapshot: &Snapshot) -> Option<&[Unit]> {
+        if let Some(&(latest, _)) = self.received.back() {
+            if snapshot.tick <= latest {
+                return None;
+            }
+        }
+        let units = match snapshot.baseline {
+            None => snapshot.units.clone(),
+            Some(baseline) => {
+                let (_, units) = self.received.iter().find(|&&(tick, _)| tick == baseline)?;
+                snapshot.apply(units)
+            }
+        };
+
+        if self.received.len() == HISTORY_LEN {
+            self.received.pop_front();
+        }
+        self.received.push_back((snapshot.tick, units));
+        self.received.back().map(|(_, units)| &units[..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Snapshot, SnapshotReceiver, SnapshotSender, HISTORY_LEN};
+    use crate::simulation::{Input, World};
+
+    #[test]
+    fn full_snapshot_without_ack() {
+        let world = World::new(1, &[10, 11]);
+        let mut sender = SnapshotSender::new();
+        let snapshot = sender.snapshot(world.tick(), world.units());
+        assert_eq!(snapshot, Snapshot::full(0, world.units()));
+    }
+
+    #[test]
+    fn deltas_carry_only_changes() {
+        let mut world = World::new(1, &[10, 11]);
+        let mut sender = SnapshotSender::new();
+        let mut receiver = SnapshotReceiver::new();
+
+        let full = sender.snapshot(world.tick(), world.units());
+        receiver.receive(&full).unwrap();
+        sender.ack(full.tick);
+
+        world.apply(10, Input::MoveTo { dest: (0.0, 9.0) }).unwrap();
+        world.step();
+        let delta = sender.snapshot(world.tick(), world.units());
+        assert_eq!(delta.baseline, Some(0));
+        assert_eq!(delta.units.len(), 1);
+        assert_eq!(delta.units[0], *world.nemo(10).unwrap());
+        assert_eq!(receiver.receive(&delta).unwrap(), world.units());
+    }
+
+    #[test]
+    fn lost_snapshots_are_skipped() {
+        let mut world = World::new(1, &[10, 11]);
+        let mut sender = SnapshotSender::new();
+        let mut receiver = SnapshotReceiver::new();
+
+        let full = sender.snapshot(world.tick(), world.units());
+        receiver.receive(&full).unwrap();
+        sender.ack(full.tick);
+
+        world.apply(10, Input::MoveTo { dest: (0.0, 9.0) }).unwrap();
+        world.step();
+        let lost = sender.snapshot(world.tick(), world.units());
+        world.step();
+        let late = sender.snapshot(world.tick(), world.units());
+        world.step();
+        let last = sender.snapshot(world.tick(), world.units());
+
+        // Everything is relative to the acked full snapshot, so any delta can be applied.
+        assert_eq!(receiver.receive(&late).unwrap().len(), world.units().len());
+        assert!(receiver.receive(&lost).is_none());
+        assert_eq!(receiver.receive(&last).unwrap(), world.units());
+    }
+
+    #[test]
+    fn stale_acks_fall_back_to_full_snapshots() {
+        let mut world = World::new(1, &[10, 11]);
+        let mut sender = SnapshotSender::new();
+        let first = sender.snapshot(world.tick(), world.units());
+        for _ in 0..HISTORY_LEN {
+            world.step();
+            sender.snapshot(world.tick(), world.units());
+        }
+
+        sender.ack(first.tick);
+        world.step();
+        let snapshot = sender.snapshot(world.tick(), world.units());
+        assert_eq!(snapshot.baseline, None);
+    }
+
+    #[test]
+    fn apply_handles_removed_units() {
+        let world = World::new(1, &[10, 11]);
+        let units = world.units();
+        let delta = Snapshot::delta(1, (0, units), &units[1..]);
+        assert_eq!(delta.removed, vec![units[0].id]);
+        assert!(delta.units.is_empty());
+        assert_eq!(delta.apply(units), units[1..].to_vec());
+    }
+}
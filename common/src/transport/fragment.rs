@@ -0,0 +1,338 @@
+//! Splitting of payloads too large for one datagram, and their reassembly.
+//!
+//! A fragment is the payload of a fragment packet, prefixed with its own header.
+//!
+//! ```text
+//! +------------------+-------------+-------------+-------+
+//! | message id (u32) | index (u16) | count (u16) | chunk |
+//! +------------------+-------------+-------------+-------+
+//! ```
+
+use super::{Config, Error};
+use std::collections::HashMap;
+use std::time::Instant;
+
+pub const HEADER_LEN: usize = 8;
+
+#[derive(PartialEq, Debug)]
+pub struct Fragment<'a> {
+    pub message_id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub chunk: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, Error> {
+        if buf.len() < HEADER_LEN {
+            return Err(Error::Malformed);
+        }
+        let fragment = Fragment {
+            message_id: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            index: u16::from_le_bytes([buf[4], buf[5]]),
+            count: u16::from_le_bytes([buf[6], buf[7]]),
+            chunk: &buf[HEADER_LEN..],
+        };
+        if fragment.index >= fragment.count {
+            return Err(Error::Malformed);
+        }
+        Ok(fragment)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.chunk.len());
+        buf.extend_from_slice(&self.message_id.to_le_bytes());
+        buf.extend_from_slice(&self.index.to_le_bytes());
+        buf.extend_from_slice(&self.count.to_le_bytes());
+        buf.extend_from_slice(self.chunk);
+        buf
+    }
+}
+
+/// Splits the payload into fragments of at most `Config::fragment_size` bytes of chunk each.
+pub fn split(message_id: u32, payload: &[u8], config: &Config) -> Result<Vec<Vec<u8>>, Error> {
+    let too_large = Error::MessageTooLarge {
+        size: payload.len(),
+        max: config.max_message_size,
+    };
+    if payload.len() > config.max_message_size {
+        return Err(too_large);
+    }
+    let chunks = payload.chunks(config.fragment_size);
+    if chunks.len() > usize::from(u16::MAX) {
+        return Err(too_large);
+    }
+
+    let count = chunks.len() as u16;
+    Ok(chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            Fragment {
+                message_id,
+                index: index as u16,
+                count,
+                chunk,
+            }
+            .to_bytes()
+        })
+        .collect())
+}
+
+struct Partial {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    reliable: bool,
+    /// When the last fragment arrived.
+    updated: Instant,
+}
+
+/// Collects fragments until their message is complete.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<u32, Partial>,
+}
+
+impl Reassembler {
+    /// Stores the fragment, returning the whole message once its last fragment arrived.
+    ///
+    /// Fragments of unreliable messages may be lost, so at most `Config::max_partial_messages` of
+    /// those are kept at once. Reliable fragments are delivered in order, so a reliable message
+    /// starting means the previous one can never complete, and only one is kept. Either kind is
+    /// dropped by `expire` once its fragments stop arriving.
+    pub fn insert(
+        &mut self,
+        fragment: &Fragment,
+        reliable: bool,
+        now: Instant,
+        config: &Config,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let max_count = config.max_message_size.div_ceil(config.fragment_size);
+        if usize::from(fragment.count) > max_count {
+            return Err(Error::MessageTooLarge {
+                size: usize::from(fragment.count) * config.fragment_size,
+                max: config.max_message_size,
+            });
+        }
+
+        if !self.partial.contains_key(&fragment.message_id) {
+            if reliable {
+                self.partial.retain(|_, p| !p.reliable);
+            } else {
+                let unreliable = self.partial.values().filter(|p| !p.reliable).count();
+                if unreliable >= config.max_partial_messages {
+                    return Err(Error::TooManyPartialMessages);
+                }
+            }
+            self.partial.insert(
+                fragment.message_id,
+                Partial {
+                    chunks: vec![None; usize::from(fragment.count)],
+                    received: 0,
+                    size: 0,
+                    reliable,
+                    updated: now,
+                },
+            );
+        }
+
+        let partial = self.partial.get_mut(&fragment.message_id).unwrap();
+        if partial.chunks.len() != usize::from(fragment.count) || partial.reliable != reliable {
+            self.partial.remove(&fragment.message_id);
+            return Err(Error::Malformed);
+        }
+        let slot = &mut partial.chunks[usize::from(fragment.index)];
+        if slot.is_some() {
+            // Unreliable fragments may arrive twice.
+            return Ok(None);
+        }
+        *slot = Some(fragment.chunk.to_vec());
+        partial.received += 1;
+        partial.updated = now;
+        partial.size += fragment.chunk.len();
+
+        if partial.size > config.max_message_size {
+            let size = partial.size;
+            self.partial.remove(&fragment.message_id);
+            return Err(Error::MessageTooLarge {
+                size,
+                max: config.max_message_size,
+            });
+        }
+        if partial.received < partial.chunks.len() {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&fragment.message_id).unwrap();
+        let mut message = Vec::with_capacity(partial.size);
+        for chunk in partial.chunks {
+            message.extend_from_slice(&chunk.unwrap());
+        }
+        Ok(Some(message))
+    }
+
+    /// Drops messages that got no fragment for `Config::fragment_timeout`. Returns how many were
+    /// dropped.
+    pub fn expire(&mut self, now: Instant, config: &Config) -> usize {
+        let before = self.partial.len();
+        self.partial
+            .retain(|_, p| now < p.updated + config.fragment_timeout);
+        before - self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split, Fragment, Reassembler};
+    use crate::transport::{Config, Error};
+    use std::time::{Duration, Instant};
+
+    fn config() -> Config {
+        Config {
+            fragment_size: 4,
+            max_message_size: 16,
+            max_partial_messages: 2,
+            fragment_timeout: Duration::from_secs(1),
+            ..Config::default()
+        }
+    }
+
+    fn insert(
+        reassembler: &mut Reassembler,
+        fragment: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        reassembler.insert(&Fragment::parse(fragment).unwrap(), false, now, &config())
+    }
+
+    #[test]
+    fn split_and_reassemble_out_of_order() {
+        let now = Instant::now();
+        let fragments = split(1, b"hello, world", &config()).unwrap();
+        assert_eq!(fragments.len(), 3);
+
+        let mut reassembler = Reassembler::default();
+        assert_eq!(insert(&mut reassembler, &fragments[2], now).unwrap(), None);
+        assert_eq!(insert(&mut reassembler, &fragments[0], now).unwrap(), None);
+        assert_eq!(insert(&mut reassembler, &fragments[0], now).unwrap(), None);
+        assert_eq!(
+            insert(&mut reassembler, &fragments[1], now).unwrap(),
+            Some(b"hello, world".to_vec())
+        );
+    }
+
+    #[test]
+    fn split_rejects_oversized_payload() {
+        match split(1, &[0; 17], &config()) {
+            Err(Error::MessageTooLarge { size: 17, max: 16 }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn insert_rejects_oversized_message() {
+        let now = Instant::now();
+        let fragment = Fragment {
+            message_id: 1,
+            index: 0,
+            count: 5,
+            chunk: b"abcd",
+        }
+        .to_bytes();
+
+        let mut reassembler = Reassembler::default();
+        match insert(&mut reassembler, &fragment, now) {
+            Err(Error::MessageTooLarge { .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn insert_limits_partial_messages() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        for message_id in 0..3 {
+            let fragments = split(message_id, b"0123456789", &config()).unwrap();
+            let result = insert(&mut reassembler, &fragments[0], now);
+            match message_id {
+                2 => assert!(result.is_err()),
+                _ => assert_eq!(result.unwrap(), None),
+            }
+        }
+    }
+
+    #[test]
+    fn expire_drops_stale_messages() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+
+        let unreliable = split(1, b"0123456789", &config()).unwrap();
+        insert(&mut reassembler, &unreliable[0], now).unwrap();
+        let reliable = split(2, b"0123456789", &config()).unwrap();
+        let first = Fragment::parse(&reliable[0]).unwrap();
+        reassembler.insert(&first, true, now, &config()).unwrap();
+
+        // A new fragment keeps its message alive.
+        let later = now + Duration::from_millis(500);
+        let second = Fragment::parse(&reliable[1]).unwrap();
+        reassembler.insert(&second, true, later, &config()).unwrap();
+        assert_eq!(reassembler.expire(now, &config()), 0);
+        assert_eq!(
+            reassembler.expire(now + Duration::from_secs(1), &config()),
+            1
+        );
+        assert_eq!(
+            reassembler.expire(later + Duration::from_secs(1), &config()),
+            1
+        );
+
+        // The unreliable message is gone, so its last fragments complete nothing.
+        let later = now + Duration::from_secs(1);
+        insert(&mut reassembler, &unreliable[1], later).unwrap();
+        assert_eq!(
+            insert(&mut reassembler, &unreliable[2], later).unwrap(),
+            None
+        );
+    }
+
+    /// Reliable fragments arrive in order, so a sender starting new messages before finishing
+    /// the previous one must not pile them up.
+    #[test]
+    fn insert_keeps_one_partial_reliable_message() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        for message_id in 0..100 {
+            let fragments = split(message_id, b"0123456789", &config()).unwrap();
+            let first = Fragment::parse(&fragments[0]).unwrap();
+            assert_eq!(
+                reassembler.insert(&first, true, now, &config()).unwrap(),
+                None
+            );
+        }
+        assert_eq!(reassembler.partial.len(), 1);
+
+        // The latest one still completes.
+        let fragments = split(99, b"0123456789", &config()).unwrap();
+        let mut message = None;
+        for fragment in &fragments[1..] {
+            let fragment = Fragment::parse(fragment).unwrap();
+            message = reassembler.insert(&fragment, true, now, &config()).unwrap();
+        }
+        assert_eq!(message, Some(b"0123456789".to_vec()));
+    }
+
+    #[test]
+    fn parse_rejects_index_out_of_range() {
+        let fragment = Fragment {
+            message_id: 1,
+            index: 2,
+            count: 2,
+            chunk: b"",
+        }
+        .to_bytes();
+        match Fragment::parse(&fragment) {
+            Err(Error::Malformed) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Reliability layer on top of a `UdpSocket`.
+//!
+//! Every payload is sent on a `Channel`. Reliable payloads carry a per-peer sequence number, are
+//! retransmitted with exponential backoff until acked, and are delivered exactly once and in
+//! order. Unreliable payloads are sent once and may be lost, duplicated or reordered, which is
+//! fine for frequent state updates that are superseded anyway.
+//!
+//! Payloads larger than `Config::fragment_size` are split into fragments that travel on the same
+//! channel, and are only delivered once all of them arrived. Payloads larger than
+//! `Config::max_message_size` are refused on both ends.
+//!
+//! Datagrams without a transport header, like the ones `nc` sends, are still accepted. They are
+//! delivered as unreliable payloads and the replies to such peers go out bare as well.
+//!
+//! State is kept for at most `Config::max_peers` peers, created on their first well-formed
+//! datagram and dropped once they are silent for `Config::peer_timeout` with nothing left to ack.
+
+mod fragment;
+mod packet;
+mod peer;
+
+use self::packet::{Kind, Packet};
+use self::peer::Peer;
+use log::{debug, warn};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Largest datagram we send. It stays below the usual path MTU, so that datagrams are not
+/// fragmented by IP, where losing any piece loses all of them.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Largest payload a UDP datagram can carry, which is what we receive into so that nothing from a
+/// careless peer gets truncated.
+const MAX_UDP_PAYLOAD: usize = 65_507;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Channel {
+    Unreliable,
+    Reliable,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The datagram looks like a transport packet but its header is broken.
+    Malformed,
+    UnsupportedVersion(u8),
+    /// The message exceeds `Config::max_message_size`.
+    MessageTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The peer has too many unreliable messages with missing fragments.
+    TooManyPartialMessages,
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How long to wait for an ack before the first retransmission.
+    pub initial_timeout: Duration,
+    /// Upper bound of the retransmission timeout, which doubles on every retry.
+    pub max_timeout: Duration,
+    /// How many times a reliable packet is sent before the peer is considered gone.
+    pub max_attempts: u32,
+    /// How many reliable packets past a gap are buffered while waiting for the gap to be filled.
+    pub receive_window: u32,
+    /// Largest payload sent in a single packet. Larger ones are fragmented.
+    pub fragment_size: usize,
+    /// Largest message sent or reassembled.
+    pub max_message_size: usize,
+    /// How long a message missing fragments is kept after its last fragment arrived.
+    pub fragment_timeout: Duration,
+    /// How many unreliable messages per peer may be missing fragments at once. Reliable ones
+    /// arrive in order, so only one of those is ever partial.
+    pub max_partial_messages: usize,
+    /// How many peers the endpoint keeps state for. Datagrams from new peers are dropped beyond.
+    pub max_peers: usize,
+    /// How long the state of a silent peer is kept once nothing sent to it waits for an ack.
+    pub peer_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            initial_timeout: Duration::from_millis(200),
+            max_timeout: Duration::from_secs(2),
+            max_attempts: 10,
+            receive_window: 256,
+            fragment_size: MAX_DATAGRAM_SIZE - packet::HEADER_LEN - fragment::HEADER_LEN,
+            max_message_size: 64 * 1024,
+            fragment_timeout: Duration::from_secs(5),
+            max_partial_messages: 16,
+            max_peers: 4096,
+            peer_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Message {
+        addr: SocketAddr,
+        channel: Channel,
+        payload: Vec<u8>,
+    },
+    /// A reliable packet to the peer was never acked. Its state is dropped.
+    Timeout { addr: SocketAddr },
+}
+
+/// Datagrams that went through the socket since the endpoint was created, acks and retransmissions
+/// included.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stats {
+    pub datagrams_received: u64,
+    pub datagrams_sent: u64,
+}
+
+pub struct Endpoint {
+    socket: UdpSocket,
+    config: Config,
+    peers: HashMap<SocketAddr, Peer>,
+    events: VecDeque<Event>,
+    buf: Vec<u8>,
+    stats: Stats,
+}
+
+/// The state of the peer a datagram came from, created if there is room for one more.
+fn admit<'a>(
+    peers: &'a mut HashMap<SocketAddr, Peer>,
+    addr: SocketAddr,
+    now: Instant,
+    config: &Config,
+) -> Option<&'a mut Peer> {
+    if !peers.contains_key(&addr) && peers.len() >= config.max_peers {
+        debug!("dropped a datagram from {}, too many peers", addr);
+        return None;
+    }
+    Some(
+        peers
+            .entry(addr)
+            .or_insert_with(|| Peer::new(random_epoch(), now)),
+    )
+}
+
+/// Picks a stream epoch. It only has to differ between runs, not to be unpredictable.
+fn random_epoch() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(nanos);
+    hasher.finish() as u32
+}
+
+impl Endpoint {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Endpoint::new(UdpSocket::bind(addr)?, Config::default()))
+    }
+
+    pub fn new(socket: UdpSocket, config: Config) -> Self {
+        Endpoint {
+            socket,
+            config,
+            peers: HashMap::new(),
+            events: VecDeque::new(),
+            buf: vec![0; MAX_UDP_PAYLOAD],
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn send(
+        &mut self,
+        addr: SocketAddr,
+        channel: Channel,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let config = &self.config;
+        let now = Instant::now();
+        let datagrams = self
+            .peers
+            .entry(addr)
+            .or_insert_with(|| Peer::new(random_epoch(), now))
+            .send(channel, payload, now, config)?;
+        for datagram in datagrams {
+            self.socket.send_to(&datagram, addr)?;
+            self.stats.datagrams_sent += 1;
+        }
+        Ok(())
+    }
+
+    /// Drops everything known about the peer, including unacked packets.
+    pub fn forget(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    /// Number of reliable packets to the peer still waiting for an ack.
+    pub fn unacked(&self, addr: &SocketAddr) -> usize {
+        self.peers.get(addr).map(|peer| peer.unacked()).unwrap_or(0)
+    }
+
+    /// Whether every reliable packet sent to any peer was acked.
+    pub fn all_acked(&self) -> bool {
+        self.peers.values().all(|peer| peer.unacked() == 0)
+    }
+
+    /// Waits for the next event, retransmitting overdue packets in the meantime. Returns `None`
+    /// once `timeout` elapsed without any event; a `timeout` of `None` waits forever.
+    pub fn recv(&mut self, timeout: Option<Duration>) -> io::Result<Option<Event>> {
+        self.recv_filtered(timeout, &mut |_| true)
+    }
+
+    /// Like `recv`, but datagrams from addresses `filter` refuses are dropped before anything
+    /// else looks at them: they are neither acked nor delivered, and create no peer.
+    pub fn recv_filtered(
+        &mut self,
+        timeout: Option<Duration>,
+        filter: &mut dyn FnMut(SocketAddr) -> bool,
+    ) -> io::Result<Option<Event>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            self.resend();
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+
+            let now = Instant::now();
+            let wake = self
+                .peers
+                .values()
+                .filter_map(|peer| peer.next_deadline())
+                .chain(deadline)
+                .min();
+            let wait = match wake {
+                Some(wake) if wake <= now => match deadline {
+                    Some(deadline) if deadline <= now => return Ok(None),
+                    _ => continue,
+                },
+                // A zero read timeout is an error, hence the floor.
+                Some(wake) => Some(::std::cmp::max(wake - now, Duration::from_millis(1))),
+                None => None,
+            };
+            self.socket.set_read_timeout(wait)?;
+
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((len, addr)) => {
+                    self.stats.datagrams_received += 1;
+                    if filter(addr) {
+                        self.handle_datagram(addr, len)
+                    }
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn handle_datagram(&mut self, addr: SocketAddr, len: usize) {
+        let now = Instant::now();
+        let buf = &self.buf[..len];
+        let config = &self.config;
+        let peers = &mut self.peers;
+
+        if !Packet::is_packet(buf) {
+            if len > config.max_message_size {
+                warn!("dropped a message of {} bytes from {}", len, addr);
+                return;
+            }
+            match admit(peers, addr, now, config) {
+                Some(peer) => peer.receive_raw(now),
+                None => return,
+            }
+            self.events.push_back(Event::Message {
+                addr,
+                channel: Channel::Unreliable,
+                payload: buf.to_vec(),
+            });
+            return;
+        }
+
+        let packet = match Packet::parse(buf) {
+            Ok(packet) => packet,
+            Err(err) => {
+                warn!("{:?} in a datagram from {}", err, addr);
+                return;
+            }
+        };
+        if packet.kind == Kind::Ack && !peers.contains_key(&addr) {
+            // Nothing was sent to this peer, or its state is gone already.
+            return;
+        }
+        let incoming = match admit(peers, addr, now, config) {
+            Some(peer) => peer.receive(&packet, now, config),
+            None => return,
+        };
+        for err in incoming.errors {
+            warn!("{:?} in a fragment from {}", err, addr);
+        }
+        if let Some(reply) = incoming.reply {
+            match self.socket.send_to(&reply, addr) {
+                Ok(_) => self.stats.datagrams_sent += 1,
+                Err(e) => warn!("couldn't ack {}: {}", addr, e),
+            }
+        }
+        for (channel, payload) in incoming.delivered {
+            self.events.push_back(Event::Message {
+                addr,
+                channel,
+                payload,
+            });
+        }
+    }
+
+    fn resend(&mut self) {
+        let now = Instant::now();
+        let config = &self.config;
+        self.peers.retain(|addr, peer| {
+            let idle = peer.is_idle(now, config);
+            if idle {
+                debug!("forgot {}, silent for {:?}", addr, config.peer_timeout);
+            }
+            !idle
+        });
+
+        let mut gone = Vec::new();
+        for (addr, peer) in &mut self.peers {
+            let expired = peer.expire_fragments(now, &self.config);
+            if expired > 0 {
+                warn!("{} incomplete messages from {} expired", expired, addr);
+            }
+            match peer.resend(now, &self.config) {
+                Ok(datagrams) => {
+                    for datagram in datagrams {
+                        match self.socket.send_to(&datagram, addr) {
+                            Ok(_) => self.stats.datagrams_sent += 1,
+                            Err(e) => warn!("couldn't resend to {}: {}", addr, e),
+                        }
+                    }
+                }
+                Err(_) => gone.push(*addr),
+            }
+        }
+        for addr in gone {
+            self.peers.remove(&addr);
+            self.events.push_back(Event::Timeout { addr });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Channel, Endpoint, Error, Event};
+    use std::time::Duration;
+
+    fn expect_message(endpoint: &mut Endpoint) -> (Channel, Vec<u8>) {
+        match endpoint.recv(Some(Duration::from_secs(5))).unwrap() {
+            Some(Event::Message {
+                channel, payload, ..
+            }) => (channel, payload),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn endpoints_exchange_messages_over_loopback() {
+        let mut server = Endpoint::bind("127.0.0.1:0").unwrap();
+        let mut client = Endpoint::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        client
+            .send(server_addr, Channel::Reliable, b"request")
+            .unwrap();
+        assert_eq!(
+            expect_message(&mut server),
+            (Channel::Reliable, b"request".to_vec())
+        );
+
+        server
+            .send(client_addr, Channel::Unreliable, b"update")
+            .unwrap();
+        assert_eq!(
+            expect_message(&mut client),
+            (Channel::Unreliable, b"update".to_vec())
+        );
+
+        // The ack from the server arrives while the client waits for anything else.
+        assert!(client
+            .recv(Some(Duration::from_millis(50)))
+            .unwrap()
+            .is_none());
+        assert_eq!(client.unacked(&server_addr), 0);
+        // The request went out, the update and the ack came in. Unreliable packets get no ack.
+        let stats = client.stats();
+        assert_eq!((stats.datagrams_sent, stats.datagrams_received), (1, 2));
+    }
+
+    #[test]
+    fn large_messages_cross_in_fragments() {
+        let mut server = Endpoint::bind("127.0.0.1:0").unwrap();
+        let mut client = Endpoint::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let message: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        client
+            .send(server_addr, Channel::Reliable, &message)
+            .unwrap();
+        assert_eq!(expect_message(&mut server), (Channel::Reliable, message));
+
+        match client.send(server_addr, Channel::Reliable, &vec![0; 64 * 1024 + 1]) {
+            Err(Error::MessageTooLarge { .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn peers_are_only_admitted_for_valid_packets_while_there_is_room() {
+        let mut server = Endpoint::new(
+            ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap(),
+            super::Config {
+                max_peers: 1,
+                ..super::Config::default()
+            },
+        );
+        let server_addr = server.local_addr().unwrap();
+        let quiet = Some(Duration::from_millis(50));
+
+        let garbage = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        garbage
+            .send_to(&[super::packet::MAGIC, 0xFF], server_addr)
+            .unwrap();
+        assert!(server.recv(quiet).unwrap().is_none());
+        assert!(server.peers.is_empty());
+
+        let mut first = Endpoint::bind("127.0.0.1:0").unwrap();
+        first
+            .send(server_addr, Channel::Reliable, b"first")
+            .unwrap();
+        assert_eq!(
+            expect_message(&mut server),
+            (Channel::Reliable, b"first".to_vec())
+        );
+
+        let mut second = Endpoint::bind("127.0.0.1:0").unwrap();
+        second
+            .send(server_addr, Channel::Reliable, b"second")
+            .unwrap();
+        assert!(server.recv(quiet).unwrap().is_none());
+        assert_eq!(server.peers.len(), 1);
+    }
+
+    #[test]
+    fn filtered_datagrams_are_neither_acked_nor_delivered() {
+        let mut server = Endpoint::bind("127.0.0.1:0").unwrap();
+        let mut client = Endpoint::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let quiet = Some(Duration::from_millis(50));
+
+        client
+            .send(server_addr, Channel::Reliable, b"refused")
+            .unwrap();
+        let mut refused = 0;
+        let mut filter = |_| {
+            refused += 1;
+            false
+        };
+        assert!(server.recv_filtered(quiet, &mut filter).unwrap().is_none());
+        assert_eq!(refused, 1);
+        assert!(server.peers.is_empty());
+        assert!(client.recv(quiet).unwrap().is_none());
+        assert_eq!(client.unacked(&server_addr), 1);
+
+        // The retransmission gets through once the filter lets it.
+        assert!(client
+            .recv(Some(Duration::from_millis(250)))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            expect_message(&mut server),
+            (Channel::Reliable, b"refused".to_vec())
+        );
+    }
+
+    #[test]
+    fn unanswered_peer_times_out() {
+        let mut endpoint = Endpoint::new(
+            ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap(),
+            super::Config {
+                initial_timeout: Duration::from_millis(5),
+                max_timeout: Duration::from_millis(10),
+                max_attempts: 3,
+                receive_window: 8,
+                ..super::Config::default()
+            },
+        );
+        // Nobody listens on this socket once it is dropped.
+        let silent = ::std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        endpoint.send(silent, Channel::Reliable, b"hello?").unwrap();
+        match endpoint.recv(Some(Duration::from_secs(5))).unwrap() {
+            Some(Event::Timeout { addr }) => assert_eq!(addr, silent),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
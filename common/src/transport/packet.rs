@@ -0,0 +1,144 @@
+//! Transport packet header.
+//!
+//! ```text
+//! +-------+---------+------+-------------+-----------+------------+---------+
+//! | magic | version | kind | epoch (u32) | seq (u32) | base (u32) | payload |
+//! +-------+---------+------+-------------+-----------+------------+---------+
+//! ```
+//!
+//! Integers are little endian. `epoch` identifies the sending stream, so that a receiver notices
+//! when its peer restarted and starts over. `seq` numbers reliable packets, and `base` is the
+//! lowest sequence number the sender still waits an ack for. Acks carry the epoch and sequence
+//! number of the packet they acknowledge. Fragment packets are sequenced like their channel, and
+//! their payload is one fragment of a larger message.
+
+use super::Error;
+
+/// First byte of every transport packet. It can start neither a JSON text nor a binary frame.
+pub const MAGIC: u8 = 0xFB;
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 15;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Unreliable = 0,
+    Reliable = 1,
+    Ack = 2,
+    ReliableFragment = 3,
+    UnreliableFragment = 4,
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Packet<'a> {
+    pub kind: Kind,
+    pub epoch: u32,
+    pub seq: u32,
+    pub base: u32,
+    pub payload: &'a [u8],
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[..4]);
+    u32::from_le_bytes(bytes)
+}
+
+impl<'a> Packet<'a> {
+    /// Whether the datagram is a transport packet, as opposed to a bare message sent by a tool
+    /// like `nc`.
+    pub fn is_packet(buf: &[u8]) -> bool {
+        buf.first() == Some(&MAGIC)
+    }
+
+    pub fn parse(buf: &'a [u8]) -> Result<Self, Error> {
+        if buf.len() < HEADER_LEN || buf[0] != MAGIC {
+            return Err(Error::Malformed);
+        }
+        if buf[1] != VERSION {
+            return Err(Error::UnsupportedVersion(buf[1]));
+        }
+        let kind = match buf[2] {
+            0 => Kind::Unreliable,
+            1 => Kind::Reliable,
+            2 => Kind::Ack,
+            3 => Kind::ReliableFragment,
+            4 => Kind::UnreliableFragment,
+            _ => return Err(Error::Malformed),
+        };
+        Ok(Packet {
+            kind,
+            epoch: read_u32(&buf[3..]),
+            seq: read_u32(&buf[7..]),
+            base: read_u32(&buf[11..]),
+            payload: &buf[HEADER_LEN..],
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push(MAGIC);
+        buf.push(VERSION);
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&self.epoch.to_le_bytes());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.extend_from_slice(&self.base.to_le_bytes());
+        buf.extend_from_slice(self.payload);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Kind, Packet, HEADER_LEN, VERSION};
+    use crate::transport::Error;
+
+    #[test]
+    fn packet_round_trip() {
+        let original = Packet {
+            kind: Kind::Reliable,
+            epoch: 0xDEAD_BEEF,
+            seq: 7,
+            base: 3,
+            payload: b"hello",
+        };
+        let bytes = original.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + 5);
+        assert!(Packet::is_packet(&bytes));
+        assert_eq!(Packet::parse(&bytes).unwrap(), original);
+    }
+
+    #[test]
+    fn json_is_not_a_packet() {
+        assert!(!Packet::is_packet(b"\"ConnectRequest\""));
+        assert!(!Packet::is_packet(b""));
+    }
+
+    #[test]
+    fn parse_rejects_bad_headers() {
+        let mut bytes = Packet {
+            kind: Kind::Ack,
+            epoch: 1,
+            seq: 2,
+            base: 0,
+            payload: &[],
+        }
+        .to_bytes();
+
+        match Packet::parse(&bytes[..HEADER_LEN - 1]) {
+            Err(Error::Malformed) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        bytes[2] = 9;
+        match Packet::parse(&bytes) {
+            Err(Error::Malformed) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        bytes[1] = VERSION + 1;
+        match Packet::parse(&bytes) {
+            Err(Error::UnsupportedVersion(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,619 @@
+//! Per-peer reliability state. Nothing here touches a socket, so it can be driven by tests.
+
+use super::fragment::{self, Fragment, Reassembler};
+use super::packet::{Kind, Packet};
+use super::{Channel, Config, Error};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// A reliable packet still waiting for its ack.
+struct Pending {
+    kind: Kind,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    timeout: Duration,
+    attempts: u32,
+}
+
+/// Result of feeding a received packet to a `Peer`.
+#[derive(Default, Debug)]
+pub struct Incoming {
+    /// Payloads ready for the application, in order.
+    pub delivered: Vec<(Channel, Vec<u8>)>,
+    /// Packet to send back to the peer, namely an ack.
+    pub reply: Option<Vec<u8>>,
+    /// Fragments that were dropped, because they were broken or their message too large.
+    pub errors: Vec<Error>,
+}
+
+/// The peer didn't ack a reliable packet after `Config::max_attempts` sends.
+#[derive(Debug)]
+pub struct GaveUp;
+
+/// Whether sequence number `a` comes before `b`. Sequence numbers wrap around, so of two numbers
+/// less than half the space apart, the one the other is ahead of comes first.
+fn precedes(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+pub struct Peer {
+    /// The peer speaks bare messages without transport headers, like `nc` does. Nothing sent to
+    /// it can be made reliable.
+    pub raw: bool,
+
+    epoch: u32,
+    next_seq: u32,
+    next_message_id: u32,
+    pending: BTreeMap<u32, Pending>,
+
+    remote_epoch: Option<u32>,
+    next_expected: u32,
+    out_of_order: BTreeMap<u32, (Kind, Vec<u8>)>,
+    reassembler: Reassembler,
+    /// When the peer last sent us anything.
+    last_heard: Instant,
+}
+
+impl Peer {
+    /// `epoch` must be chosen at random, so that the remote can tell this stream apart from a
+    /// previous one with the same address.
+    pub fn new(epoch: u32, now: Instant) -> Self {
+        Peer {
+            raw: false,
+            epoch,
+            next_seq: 0,
+            next_message_id: 0,
+            pending: BTreeMap::new(),
+            remote_epoch: None,
+            next_expected: 0,
+            out_of_order: BTreeMap::new(),
+            reassembler: Reassembler::default(),
+            last_heard: now,
+        }
+    }
+
+    /// Oldest sequence number still waiting for an ack.
+    fn base(&self) -> u32 {
+        self.pending
+            .keys()
+            .cloned()
+            .max_by_key(|&seq| self.next_seq.wrapping_sub(seq))
+            .unwrap_or(self.next_seq)
+    }
+
+    fn packet(&self, kind: Kind, seq: u32, payload: &[u8]) -> Vec<u8> {
+        Packet {
+            kind,
+            epoch: self.epoch,
+            seq,
+            base: self.base(),
+            payload,
+        }
+        .to_bytes()
+    }
+
+    /// Wraps the payload into datagrams for this peer, splitting it into fragments when it exceeds
+    /// `Config::fragment_size`. Reliable payloads are kept until acked.
+    pub fn send(
+        &mut self,
+        channel: Channel,
+        payload: &[u8],
+        now: Instant,
+        config: &Config,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        if payload.len() > config.max_message_size {
+            return Err(Error::MessageTooLarge {
+                size: payload.len(),
+                max: config.max_message_size,
+            });
+        }
+        if self.raw {
+            return Ok(vec![payload.to_vec()]);
+        }
+
+        if payload.len() <= config.fragment_size {
+            let kind = match channel {
+                Channel::Unreliable => Kind::Unreliable,
+                Channel::Reliable => Kind::Reliable,
+            };
+            return Ok(vec![self.send_packet(kind, payload.to_vec(), now, config)]);
+        }
+
+        let kind = match channel {
+            Channel::Unreliable => Kind::UnreliableFragment,
+            Channel::Reliable => Kind::ReliableFragment,
+        };
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        Ok(fragment::split(message_id, payload, config)?
+            .into_iter()
+            .map(|fragment| self.send_packet(kind, fragment, now, config))
+            .collect())
+    }
+
+    fn send_packet(
+        &mut self,
+        kind: Kind,
+        payload: Vec<u8>,
+        now: Instant,
+        config: &Config,
+    ) -> Vec<u8> {
+        match kind {
+            Kind::Reliable | Kind::ReliableFragment => {
+                let seq = self.next_seq;
+                self.next_seq = self.next_seq.wrapping_add(1);
+                self.pending.insert(
+                    seq,
+                    Pending {
+                        kind,
+                        payload,
+                        sent_at: now,
+                        timeout: config.initial_timeout,
+                        attempts: 1,
+                    },
+                );
+                self.packet(kind, seq, &self.pending[&seq].payload)
+            }
+            _ => self.packet(kind, 0, &payload),
+        }
+    }
+
+    pub fn receive(&mut self, packet: &Packet, now: Instant, config: &Config) -> Incoming {
+        self.raw = false;
+        self.last_heard = now;
+        let mut incoming = Incoming::default();
+        match packet.kind {
+            Kind::Unreliable => {
+                incoming
+                    .delivered
+                    .push((Channel::Unreliable, packet.payload.to_vec()));
+            }
+            Kind::UnreliableFragment => {
+                self.reassemble(
+                    Channel::Unreliable,
+                    packet.payload,
+                    now,
+                    config,
+                    &mut incoming,
+                );
+            }
+            Kind::Ack => {
+                if packet.epoch == self.epoch {
+                    self.pending.remove(&packet.seq);
+                }
+            }
+            Kind::Reliable | Kind::ReliableFragment => {
+                if self.remote_epoch != Some(packet.epoch) {
+                    // A new stream, either because we just met or because the peer restarted.
+                    self.remote_epoch = Some(packet.epoch);
+                    self.next_expected = packet.base;
+                    self.out_of_order.clear();
+                    self.reassembler = Reassembler::default();
+                } else if precedes(self.next_expected, packet.base) {
+                    // Everything below `base` was acked, by a previous incarnation of ours.
+                    self.next_expected = packet.base;
+                    self.out_of_order
+                        .retain(|&seq, _| !precedes(seq, packet.base));
+                    self.reassembler = Reassembler::default();
+                }
+
+                let seq = packet.seq;
+                if seq.wrapping_sub(self.next_expected) >= config.receive_window {
+                    // Either a duplicate, or too far ahead to buffer. Only a duplicate is acked;
+                    // the sender will retry the other one later.
+                    if precedes(seq, self.next_expected) {
+                        incoming.reply = Some(self.ack(packet.epoch, seq));
+                    }
+                    return incoming;
+                }
+
+                incoming.reply = Some(self.ack(packet.epoch, seq));
+                self.out_of_order
+                    .entry(seq)
+                    .or_insert_with(|| (packet.kind, packet.payload.to_vec()));
+                while let Some((kind, payload)) = self.out_of_order.remove(&self.next_expected) {
+                    self.next_expected = self.next_expected.wrapping_add(1);
+                    match kind {
+                        Kind::ReliableFragment => {
+                            self.reassemble(Channel::Reliable, &payload, now, config, &mut incoming)
+                        }
+                        _ => incoming.delivered.push((Channel::Reliable, payload)),
+                    }
+                }
+            }
+        }
+        incoming
+    }
+
+    fn reassemble(
+        &mut self,
+        channel: Channel,
+        buf: &[u8],
+        now: Instant,
+        config: &Config,
+        incoming: &mut Incoming,
+    ) {
+        let reliable = channel == Channel::Reliable;
+        let result = Fragment::parse(buf)
+            .and_then(|fragment| self.reassembler.insert(&fragment, reliable, now, config));
+        match result {
+            Ok(Some(message)) => incoming.delivered.push((channel, message)),
+            Ok(None) => {}
+            Err(err) => incoming.errors.push(err),
+        }
+    }
+
+    fn ack(&self, epoch: u32, seq: u32) -> Vec<u8> {
+        Packet {
+            kind: Kind::Ack,
+            epoch,
+            seq,
+            base: 0,
+            payload: &[],
+        }
+        .to_bytes()
+    }
+
+    /// Takes note of a bare message from the peer, which makes it a raw peer.
+    pub fn receive_raw(&mut self, now: Instant) {
+        self.raw = true;
+        self.last_heard = now;
+    }
+
+    /// Whether the peer has been silent for `Config::peer_timeout` and nothing sent to it waits
+    /// for an ack, so that its state can go.
+    pub fn is_idle(&self, now: Instant, config: &Config) -> bool {
+        self.pending.is_empty() && self.last_heard + config.peer_timeout <= now
+    }
+
+    /// Datagrams to send again because their ack is overdue. Each retry doubles the timeout of the
+    /// packet, up to `Config::max_timeout`.
+    pub fn resend(&mut self, now: Instant, config: &Config) -> Result<Vec<Vec<u8>>, GaveUp> {
+        let mut due = Vec::new();
+        for (&seq, pending) in &mut self.pending {
+            if now < pending.sent_at + pending.timeout {
+                continue;
+            }
+            if pending.attempts >= config.max_attempts {
+                return Err(GaveUp);
+            }
+            pending.attempts += 1;
+            pending.sent_at = now;
+            pending.timeout = ::std::cmp::min(pending.timeout * 2, config.max_timeout);
+            due.push(seq);
+        }
+
+        // The base may have moved since the first send, so the packets are rebuilt.
+        Ok(due
+            .into_iter()
+            .map(|seq| {
+                let pending = &self.pending[&seq];
+                self.packet(pending.kind, seq, &pending.payload)
+            })
+            .collect())
+    }
+
+    /// Drops messages that got none of their missing fragments for `Config::fragment_timeout`.
+    /// Returns how many were dropped.
+    pub fn expire_fragments(&mut self, now: Instant, config: &Config) -> usize {
+        self.reassembler.expire(now, config)
+    }
+
+    /// When `resend` should be called next, if anything is waiting for an ack.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|pending| pending.sent_at + pending.timeout)
+            .min()
+    }
+
+    /// Number of reliable packets waiting for an ack.
+    pub fn unacked(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Peer;
+    use crate::transport::packet::{Kind, Packet};
+    use crate::transport::{Channel, Config, Error};
+    use std::time::{Duration, Instant};
+
+    fn config() -> Config {
+        Config {
+            initial_timeout: Duration::from_millis(100),
+            max_timeout: Duration::from_millis(300),
+            max_attempts: 4,
+            receive_window: 8,
+            fragment_size: 8,
+            max_message_size: 64,
+            ..Config::default()
+        }
+    }
+
+    /// Sends a reliable payload that fits in a single datagram.
+    fn send(from: &mut Peer, payload: &[u8], now: Instant) -> Vec<u8> {
+        let mut datagrams = from
+            .send(Channel::Reliable, payload, now, &config())
+            .unwrap();
+        assert_eq!(datagrams.len(), 1);
+        datagrams.pop().unwrap()
+    }
+
+    /// Feeds `datagram` from one peer to another, returning the delivered payloads and the reply.
+    fn deliver(to: &mut Peer, datagram: &[u8]) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        let packet = Packet::parse(datagram).unwrap();
+        let incoming = to.receive(&packet, Instant::now(), &config());
+        let payloads = incoming.delivered.into_iter().map(|(_, p)| p).collect();
+        (payloads, incoming.reply)
+    }
+
+    #[test]
+    fn reliable_packets_are_delivered_in_order() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        let mut bob = Peer::new(2, now);
+
+        let first = send(&mut alice, b"first", now);
+        let second = send(&mut alice, b"second", now);
+        let third = send(&mut alice, b"third", now);
+
+        assert_eq!(deliver(&mut bob, &second).0, Vec::<Vec<u8>>::new());
+        assert_eq!(deliver(&mut bob, &third).0, Vec::<Vec<u8>>::new());
+        assert_eq!(
+            deliver(&mut bob, &first).0,
+            vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        let mut bob = Peer::new(2, now);
+        alice.next_seq = u32::MAX - 1;
+
+        let packets: Vec<_> = (0..4u8).map(|i| send(&mut alice, &[i], now)).collect();
+        assert_eq!(alice.next_seq, 2);
+
+        // The ack of the oldest packet moves the base past the wrap.
+        let (payloads, ack) = deliver(&mut bob, &packets[0]);
+        assert_eq!(payloads, vec![vec![0]]);
+        deliver(&mut alice, &ack.unwrap());
+        assert_eq!(alice.base(), u32::MAX);
+
+        // Across the wrap, order is kept and old packets are still told from new ones.
+        assert!(deliver(&mut bob, &packets[3]).0.is_empty());
+        assert!(deliver(&mut bob, &packets[2]).0.is_empty());
+        assert_eq!(
+            deliver(&mut bob, &packets[1]).0,
+            vec![vec![1], vec![2], vec![3]]
+        );
+        let (payloads, reply) = deliver(&mut bob, &packets[0]);
+        assert!(payloads.is_empty());
+        assert!(reply.is_some());
+    }
+
+    #[test]
+    fn silent_peer_becomes_idle_once_everything_is_acked() {
+        let now = Instant::now();
+        let config = Config {
+            peer_timeout: Duration::from_secs(10),
+            ..config()
+        };
+        let mut alice = Peer::new(1, now);
+        let mut bob = Peer::new(2, now);
+        let later = now + Duration::from_secs(10);
+        assert!(alice.is_idle(later, &config));
+
+        let packet = send(&mut alice, b"hi", now);
+        assert!(!alice.is_idle(later, &config));
+
+        let (_, ack) = deliver(&mut bob, &packet);
+        alice.receive(&Packet::parse(&ack.unwrap()).unwrap(), later, &config);
+        assert!(!alice.is_idle(later, &config));
+        assert!(alice.is_idle(later + Duration::from_secs(10), &config));
+    }
+
+    #[test]
+    fn duplicates_are_dropped_but_acked() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        let mut bob = Peer::new(2, now);
+
+        let packet = send(&mut alice, b"once", now);
+        assert_eq!(deliver(&mut bob, &packet).0, vec![b"once".to_vec()]);
+
+        let (payloads, reply) = deliver(&mut bob, &packet);
+        assert!(payloads.is_empty());
+        assert!(reply.is_some());
+    }
+
+    #[test]
+    fn acks_stop_retransmission() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        let mut bob = Peer::new(2, now);
+
+        let packet = send(&mut alice, b"hi", now);
+        assert_eq!(alice.unacked(), 1);
+
+        let (_, ack) = deliver(&mut bob, &packet);
+        deliver(&mut alice, &ack.unwrap());
+        assert_eq!(alice.unacked(), 0);
+        assert!(alice.next_deadline().is_none());
+
+        let later = now + Duration::from_secs(10);
+        assert!(alice.resend(later, &config()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn retransmission_backs_off_and_gives_up() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        send(&mut alice, b"hello?", now);
+
+        assert!(alice.resend(now, &config()).unwrap().is_empty());
+        assert_eq!(
+            alice.next_deadline(),
+            Some(now + Duration::from_millis(100))
+        );
+
+        let t = now + Duration::from_millis(100);
+        assert_eq!(alice.resend(t, &config()).unwrap().len(), 1);
+        assert_eq!(alice.next_deadline(), Some(t + Duration::from_millis(200)));
+
+        let t = t + Duration::from_millis(200);
+        assert_eq!(alice.resend(t, &config()).unwrap().len(), 1);
+        assert_eq!(alice.next_deadline(), Some(t + Duration::from_millis(300)));
+
+        let t = t + Duration::from_millis(300);
+        assert_eq!(alice.resend(t, &config()).unwrap().len(), 1);
+
+        let t = t + Duration::from_millis(300);
+        assert!(alice.resend(t, &config()).is_err());
+    }
+
+    #[test]
+    fn packets_beyond_the_window_are_not_acked() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        let mut bob = Peer::new(2, now);
+
+        let first = send(&mut alice, b"0", now);
+        let mut packets = Vec::new();
+        for _ in 0..config().receive_window {
+            packets.push(send(&mut alice, b"n", now));
+        }
+
+        let (payloads, reply) = deliver(&mut bob, packets.last().unwrap());
+        assert!(payloads.is_empty());
+        assert!(reply.is_none());
+
+        deliver(&mut bob, &first);
+        let (payloads, reply) = deliver(&mut bob, packets.last().unwrap());
+        assert!(payloads.is_empty());
+        assert!(reply.is_some());
+    }
+
+    #[test]
+    fn restarted_peer_starts_a_new_stream() {
+        let now = Instant::now();
+        let mut bob = Peer::new(2, now);
+
+        let mut alice = Peer::new(1, now);
+        for _ in 0..3 {
+            let packet = send(&mut alice, b"old", now);
+            deliver(&mut bob, &packet);
+        }
+
+        let mut alice = Peer::new(3, now);
+        let packet = send(&mut alice, b"new", now);
+        assert_eq!(deliver(&mut bob, &packet).0, vec![b"new".to_vec()]);
+    }
+
+    #[test]
+    fn restarted_receiver_catches_up_with_the_stream() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+
+        let mut bob = Peer::new(2, now);
+        for _ in 0..3 {
+            let packet = send(&mut alice, b"old", now);
+            let (_, ack) = deliver(&mut bob, &packet);
+            deliver(&mut alice, &ack.unwrap());
+        }
+
+        let mut bob = Peer::new(4, now);
+        let packet = send(&mut alice, b"new", now);
+        assert_eq!(deliver(&mut bob, &packet).0, vec![b"new".to_vec()]);
+    }
+
+    #[test]
+    fn stale_acks_are_ignored() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        send(&mut alice, b"hi", now);
+
+        let stale = Packet {
+            kind: Kind::Ack,
+            epoch: 99,
+            seq: 0,
+            base: 0,
+            payload: &[],
+        }
+        .to_bytes();
+        deliver(&mut alice, &stale);
+        assert_eq!(alice.unacked(), 1);
+    }
+
+    #[test]
+    fn raw_peer_gets_bare_payloads() {
+        let now = Instant::now();
+        let mut peer = Peer::new(1, now);
+        peer.raw = true;
+
+        let datagrams = peer
+            .send(Channel::Reliable, b"\"DisconnectResponse\"", now, &config())
+            .unwrap();
+        assert_eq!(datagrams, vec![b"\"DisconnectResponse\"".to_vec()]);
+        assert_eq!(peer.unacked(), 0);
+    }
+
+    #[test]
+    fn large_reliable_messages_are_fragmented() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        let mut bob = Peer::new(2, now);
+
+        let message = b"a message spanning four packets!";
+        let datagrams = alice
+            .send(Channel::Reliable, message, now, &config())
+            .unwrap();
+        assert_eq!(datagrams.len(), 4);
+        assert_eq!(alice.unacked(), 4);
+
+        // A lost fragment is retransmitted as a fragment.
+        for datagram in &datagrams[1..] {
+            assert!(deliver(&mut bob, datagram).0.is_empty());
+        }
+        let later = now + Duration::from_millis(100);
+        let resent = alice.resend(later, &config()).unwrap();
+        assert_eq!(resent.len(), 4);
+        assert_eq!(deliver(&mut bob, &resent[0]).0, vec![message.to_vec()]);
+    }
+
+    #[test]
+    fn large_unreliable_messages_are_reassembled_in_any_order() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        let mut bob = Peer::new(2, now);
+
+        let message = b"0123456789abcdef0123";
+        let mut datagrams = alice
+            .send(Channel::Unreliable, message, now, &config())
+            .unwrap();
+        assert_eq!(alice.unacked(), 0);
+
+        datagrams.reverse();
+        let last = datagrams.pop().unwrap();
+        for datagram in &datagrams {
+            let (payloads, reply) = deliver(&mut bob, datagram);
+            assert!(payloads.is_empty());
+            assert!(reply.is_none());
+        }
+        assert_eq!(deliver(&mut bob, &last).0, vec![message.to_vec()]);
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let now = Instant::now();
+        let mut alice = Peer::new(1, now);
+        match alice.send(Channel::Reliable, &[0; 65], now, &config()) {
+            Err(Error::MessageTooLarge { size: 65, max: 64 }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(alice.unacked(), 0);
+    }
+}
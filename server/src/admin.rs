@@ -0,0 +1,178 @@
+//! The admin console, a line based text protocol on a loopback TCP port. Every line is a command,
+//! answered by lines of text and an empty line. Commands are run by the lobby, on live state.
+//!
+//! Anyone on the machine may connect, so the port is never reachable from elsewhere.
+
+use crate::net::Inbound;
+use common::manager::Id;
+use log::LevelFilter;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+pub const HELP: &str = "\
+users               List connected users
+games               List open games
+kick <user id>      Disconnect a user
+close <game id>     Close a game, taking its players out
+rate <user id> <n>  Set the rating of a user, used the next time they queue
+broadcast <text>    Push a message to every user
+loglevel <level>    Change the log level: off, error, warn, info, debug or trace
+help                Show this message";
+
+#[derive(Debug)]
+pub enum Command {
+    Users,
+    Games,
+    Kick(Id),
+    Close(Id),
+    Rate(Id, u32),
+    Broadcast(String),
+    LogLevel(LevelFilter),
+    Help,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, arg) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        };
+        let id = || {
+            arg.parse()
+                .map_err(|_| format!("`{}` needs an id, got `{}`", name, arg))
+        };
+        match name {
+            "users" => Ok(Command::Users),
+            "games" => Ok(Command::Games),
+            "kick" => Ok(Command::Kick(id()?)),
+            "close" => Ok(Command::Close(id()?)),
+            "rate" => {
+                let mut args = arg.split_whitespace();
+                match (args.next().map(str::parse), args.next().map(str::parse)) {
+                    (Some(Ok(user_id)), Some(Ok(rating))) if args.next().is_none() => {
+                        Ok(Command::Rate(user_id, rating))
+                    }
+                    _ => Err(format!(
+                        "`rate` needs a user id and a rating, got `{}`",
+                        arg
+                    )),
+                }
+            }
+            "broadcast" if !arg.is_empty() => Ok(Command::Broadcast(arg.to_string())),
+            "broadcast" => Err("`broadcast` needs a text".to_string()),
+            "loglevel" => arg
+                .parse()
+                .map(Command::LogLevel)
+                .map_err(|_| format!("unknown log level `{}`", arg)),
+            "help" => Ok(Command::Help),
+            _ => Err(format!("unknown command `{}`, try `help`", name)),
+        }
+    }
+}
+
+/// Starts listening on the loopback `port`, passing commands to the lobby through `inbound`.
+pub fn spawn(port: u16, inbound: Sender<Inbound>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let addr = listener.local_addr()?;
+    thread::Builder::new()
+        .name("admin".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("couldn't accept an admin connection: {}", e);
+                        continue;
+                    }
+                };
+                let inbound = inbound.clone();
+                let spawned = thread::Builder::new()
+                    .name("admin session".to_string())
+                    .spawn(move || {
+                        if let Err(e) = serve(stream, &inbound) {
+                            warn!("admin connection failed: {}", e);
+                        }
+                    });
+                if let Err(e) = spawned {
+                    error!("couldn't serve an admin connection: {}", e);
+                }
+            }
+        })?;
+    Ok(addr)
+}
+
+fn serve(stream: TcpStream, inbound: &Sender<Inbound>) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    info!("Admin connected from {}", peer);
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match Command::parse(&line) {
+            Ok(command) => {
+                let (reply, answer) = mpsc::channel();
+                if inbound.send(Inbound::Admin { command, reply }).is_err() {
+                    writeln!(writer, "the server is stopping\n")?;
+                    break;
+                }
+                answer
+                    .recv()
+                    .unwrap_or_else(|_| "the server is stopping".to_string())
+            }
+            Err(message) => message,
+        };
+        writeln!(writer, "{}\n", answer.trim_end())?;
+    }
+    info!("Admin from {} disconnected", peer);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Command;
+    use log::LevelFilter;
+
+    #[test]
+    fn parse_commands() {
+        assert!(matches!(Command::parse("users"), Ok(Command::Users)));
+        assert!(matches!(Command::parse("  games \n"), Ok(Command::Games)));
+        assert!(matches!(Command::parse("kick 3"), Ok(Command::Kick(3))));
+        assert!(matches!(Command::parse("close  7"), Ok(Command::Close(7))));
+        assert!(matches!(
+            Command::parse("rate 3 1500"),
+            Ok(Command::Rate(3, 1500))
+        ));
+        assert!(matches!(
+            Command::parse("loglevel debug"),
+            Ok(Command::LogLevel(LevelFilter::Debug))
+        ));
+        assert!(matches!(Command::parse("help"), Ok(Command::Help)));
+        match Command::parse("broadcast  back in  5 minutes ") {
+            Ok(Command::Broadcast(text)) => assert_eq!(text, "back in  5 minutes"),
+            other => panic!("expected a broadcast, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_refuses_bad_arguments() {
+        for line in &[
+            "",
+            "user",
+            "kick",
+            "kick me",
+            "close -1",
+            "rate 3",
+            "rate 3 high",
+            "rate 3 1500 1600",
+            "broadcast",
+            "loglevel loud",
+        ] {
+            assert!(Command::parse(line).is_err(), "`{}` was accepted", line);
+        }
+    }
+}
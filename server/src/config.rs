@@ -0,0 +1,396 @@
+//! Server settings, from defaults, an optional config file and command line flags, in increasing
+//! order of precedence.
+//!
+//! The config file holds one `key = value` per line. Blank lines and lines starting with `#` are
+//! ignored. Every key can also be given as a flag, like `--max-users 100` for `max_users = 100`.
+
+use log::LevelFilter;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: server [options]
+
+Options:
+    --config <path>            Read settings from a file of `key = value` lines
+    --bind <address>           Address to listen on [default: 0.0.0.0]
+    --port <port>              UDP port to listen on [default: 4567]
+    --admin-port <port>        Loopback TCP port of the admin console, 0 for none [default: 0]
+    --metrics-port <port>      Loopback HTTP port of the Prometheus metrics, 0 for none [default: 0]
+    --max-users <n>            Users connected at once [default: 1024]
+    --max-games <n>            Games open at once [default: 256]
+    --max-users-per-ip <n>     Users connected at once from one IP address [default: 8]
+    --rate-limit <n>           Datagrams per second from one address [default: 50]
+    --rate-burst <n>           Datagrams one address may send at once [default: 100]
+    --ban-threshold <n>        Datagrams over the limit within 10 seconds that ban the host [default: 200]
+    --ban-duration <seconds>   How long a host stays banned [default: 60]
+    --idle-timeout <seconds>   Disconnect users silent for this long [default: 60]
+    --reconnect-window <seconds>
+                               How long silent players keep their games to reconnect [default: 30]
+    --sweep-interval <seconds> How often to look for idle users [default: 5]
+    --shutdown-grace <seconds> How long games may go on after SIGINT or SIGTERM [default: 30]
+    --workers <n>              Threads ticking the games [default: number of CPUs]
+    --spectator-delay <seconds>
+                               How late spectators see games, 0 for live [default: 0]
+    --replay-dir <path>        Directory to record matches into, empty for none [default: replays]
+    --log-level <level>        off, error, warn, info, debug or trace [default: info]
+    --help                     Show this message
+
+Exit status:
+    0  stopped after every game ended
+    1  couldn't start
+    2  invalid options
+    3  stopped while games were still running, at the end of the grace period or on a second
+       signal, abandoning them";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Port of the admin console on the loopback interface, or 0 for no console.
+    pub admin_port: u16,
+    /// Port serving `/metrics` over HTTP on the loopback interface, or 0 for none.
+    pub metrics_port: u16,
+    /// Connections beyond this many users are refused.
+    pub max_users: usize,
+    /// Games beyond this many are refused.
+    pub max_games: usize,
+    /// Connections beyond this many users from one IP address are refused.
+    pub max_users_per_ip: usize,
+    /// Datagrams per second each source address may send, on average.
+    pub rate_limit: f64,
+    /// Datagrams each source address may send in a burst.
+    pub rate_burst: u32,
+    /// Dropped datagrams within a few seconds after which the sending host is banned.
+    pub ban_threshold: u32,
+    pub ban_duration: Duration,
+    /// Users not heard from for this long are disconnected, leaving their games.
+    pub idle_timeout: Duration,
+    /// How long players who went silent, or whose address stopped acknowledging, keep their games
+    /// for a `ReconnectRequest` before they are disconnected.
+    pub reconnect_window: Duration,
+    /// How often users are checked for idleness.
+    pub sweep_interval: Duration,
+    /// How long games in progress may go on once the server is asked to stop. They are abandoned
+    /// afterwards.
+    pub shutdown_grace: Duration,
+    /// Number of game worker threads, among which started games are spread.
+    pub workers: usize,
+    /// How far behind the players spectators see a game, so that they can't tell the players
+    /// what the opponent does.
+    pub spectator_delay: Duration,
+    /// Directory every match is recorded into when it ends, or `None` not to record them.
+    pub replay_dir: Option<PathBuf>,
+    pub log_level: LevelFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 4567,
+            admin_port: 0,
+            metrics_port: 0,
+            max_users: 1024,
+            max_games: 256,
+            max_users_per_ip: 8,
+            rate_limit: 50.0,
+            rate_burst: 100,
+            ban_threshold: 200,
+            ban_duration: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(60),
+            reconnect_window: Duration::from_secs(30),
+            sweep_interval: Duration::from_secs(5),
+            shutdown_grace: Duration::from_secs(30),
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            spectator_delay: Duration::from_secs(0),
+            replay_dir: Some(PathBuf::from("replays")),
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(String, io::Error),
+    /// Line `.1` of the file `.0` is not a `key = value` pair.
+    Syntax(String, usize),
+    UnknownKey(String),
+    UnknownFlag(String),
+    MissingValue(String),
+    InvalidValue {
+        key: String,
+        value: String,
+    },
+    /// The settings are fine one by one, but not together.
+    Inconsistent(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref path, ref err) => write!(f, "couldn't read {}: {}", path, err),
+            Error::Syntax(ref path, line) => {
+                write!(f, "{}:{}: expected `key = value`", path, line)
+            }
+            Error::UnknownKey(ref key) => write!(f, "unknown setting `{}`", key),
+            Error::UnknownFlag(ref flag) => write!(f, "unknown flag `{}`", flag),
+            Error::MissingValue(ref flag) => write!(f, "`{}` needs a value", flag),
+            Error::InvalidValue { ref key, ref value } => {
+                write!(f, "invalid value `{}` for `{}`", value, key)
+            }
+            Error::Inconsistent(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+fn parse<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value.parse().map_err(|_| Error::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+    })
+}
+
+fn parse_seconds(key: &str, value: &str) -> Result<Duration, Error> {
+    let seconds: f64 = parse(key, value)?;
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(Error::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        });
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+/// Like `parse_seconds`, but zero is allowed.
+fn parse_delay(key: &str, value: &str) -> Result<Duration, Error> {
+    if value.parse::<f64>().ok() == Some(0.0) {
+        return Ok(Duration::from_secs(0));
+    }
+    parse_seconds(key, value)
+}
+
+impl Config {
+    /// Builds the settings from the command line arguments, without the program name.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Error> {
+        let mut flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.find('=') {
+                Some(i) => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+                None => (arg.clone(), None),
+            };
+            if !flag.starts_with("--") {
+                return Err(Error::UnknownFlag(arg));
+            }
+            let value = match value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(Error::MissingValue(flag)),
+            };
+            flags.push((flag, value));
+        }
+
+        let mut config = Config::default();
+        for (_, path) in flags.iter().filter(|(flag, _)| flag == "--config") {
+            config.load(path)?;
+        }
+        for (flag, value) in flags.iter().filter(|(flag, _)| flag != "--config") {
+            let key = flag[2..].replace('-', "_");
+            config.set(&key, value).map_err(|err| match err {
+                Error::UnknownKey(_) => Error::UnknownFlag(flag.clone()),
+                err => err,
+            })?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Applies the settings of a config file.
+    pub fn load(&mut self, path: &str) -> Result<(), Error> {
+        let file = File::open(path).map_err(|err| Error::Io(path.to_string(), err))?;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|err| Error::Io(path.to_string(), err))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => self.set(key.trim(), value.trim())?,
+                _ => return Err(Error::Syntax(path.to_string(), i + 1)),
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "bind" => self.bind = parse(key, value)?,
+            "port" => self.port = parse(key, value)?,
+            "admin_port" => self.admin_port = parse(key, value)?,
+            "metrics_port" => self.metrics_port = parse(key, value)?,
+            "max_users" => self.max_users = parse(key, value)?,
+            "max_games" => self.max_games = parse(key, value)?,
+            "max_users_per_ip" => self.max_users_per_ip = parse(key, value)?,
+            "rate_limit" => self.rate_limit = parse(key, value)?,
+            "rate_burst" => self.rate_burst = parse(key, value)?,
+            "ban_threshold" => self.ban_threshold = parse(key, value)?,
+            "ban_duration" => self.ban_duration = parse_seconds(key, value)?,
+            "idle_timeout" => self.idle_timeout = parse_seconds(key, value)?,
+            "reconnect_window" => self.reconnect_window = parse_seconds(key, value)?,
+            "sweep_interval" => self.sweep_interval = parse_seconds(key, value)?,
+            "shutdown_grace" => self.shutdown_grace = parse_seconds(key, value)?,
+            "workers" => self.workers = parse(key, value)?,
+            "spectator_delay" => self.spectator_delay = parse_delay(key, value)?,
+            "replay_dir" if value.is_empty() => self.replay_dir = None,
+            "replay_dir" => self.replay_dir = Some(PathBuf::from(value)),
+            "log_level" => self.log_level = parse(key, value)?,
+            _ => return Err(Error::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let counts = [
+            self.max_users,
+            self.max_games,
+            self.max_users_per_ip,
+            self.workers,
+            self.rate_burst as usize,
+            self.ban_threshold as usize,
+        ];
+        if counts.contains(&0) {
+            return Err(Error::Inconsistent(
+                "max_users, max_games, max_users_per_ip, workers, rate_burst and ban_threshold \
+                 must be at least 1"
+                    .to_string(),
+            ));
+        }
+        if !self.rate_limit.is_finite() || self.rate_limit <= 0.0 {
+            return Err(Error::Inconsistent(
+                "rate_limit must be a positive number".to_string(),
+            ));
+        }
+        if self.idle_timeout < self.sweep_interval {
+            return Err(Error::Inconsistent(format!(
+                "idle_timeout ({:?}) must not be shorter than sweep_interval ({:?})",
+                self.idle_timeout, self.sweep_interval
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Error};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::Duration;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    /// Writes `contents` to a config file named after the test, returning its path.
+    fn config_file(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("fate-{}-{}.conf", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = config_file(
+            "precedence",
+            "# capacity\n\nmax_users = 10\n  max_games=5  \nidle_timeout = 12.5\n",
+        );
+        let line = format!("--max-users 20 --config {} --port=4600", path);
+        let config = Config::from_args(args(&line)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.max_users, 20);
+        assert_eq!(config.max_games, 5);
+        assert_eq!(config.idle_timeout, Duration::from_millis(12500));
+        assert_eq!(config.port, 4600);
+        assert_eq!(config.max_users_per_ip, Config::default().max_users_per_ip);
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        match Config::from_args(args("--max-user 10")) {
+            Err(Error::UnknownFlag(ref flag)) if flag == "--max-user" => {}
+            other => panic!("expected an unknown flag, got {:?}", other),
+        }
+        match Config::from_args(args("max_users 10")) {
+            Err(Error::UnknownFlag(ref flag)) if flag == "max_users" => {}
+            other => panic!("expected an unknown flag, got {:?}", other),
+        }
+
+        let path = config_file("unknown", "max_users = 10\nmax_user = 10\n");
+        let result = Config::from_args(args(&format!("--config {}", path)));
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::UnknownKey(ref key)) if key == "max_user" => {}
+            other => panic!("expected an unknown key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn malformed_lines_and_values_are_refused() {
+        let path = config_file("syntax", "max_users = 10\nmax_games\n");
+        let result = Config::from_args(args(&format!("--config {}", path)));
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::Syntax(_, 2)) => {}
+            other => panic!("expected a syntax error on line 2, got {:?}", other),
+        }
+
+        for line in &[
+            "--port 70000",
+            "--max-users ten",
+            "--idle-timeout 0",
+            "--idle-timeout -1",
+            "--spectator-delay inf",
+            "--log-level loud",
+        ] {
+            match Config::from_args(args(line)) {
+                Err(Error::InvalidValue { .. }) => {}
+                other => panic!("expected `{}` to be invalid, got {:?}", line, other),
+            }
+        }
+        match Config::from_args(args("--port")) {
+            Err(Error::MissingValue(ref flag)) if flag == "--port" => {}
+            other => panic!("expected a missing value, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn inconsistent_settings_are_refused() {
+        for line in &[
+            "--max-games 0",
+            "--workers 0",
+            "--rate-limit 0",
+            "--rate-limit NaN",
+            "--idle-timeout 1 --sweep-interval 2",
+        ] {
+            match Config::from_args(args(line)) {
+                Err(Error::Inconsistent(_)) => {}
+                other => panic!("expected `{}` to be inconsistent, got {:?}", line, other),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_values_turn_features_off() {
+        let config = Config::from_args(args("--replay-dir= --spectator-delay 0")).unwrap();
+        assert_eq!(config.replay_dir, None);
+        assert_eq!(config.spectator_delay, Duration::from_secs(0));
+    }
+}
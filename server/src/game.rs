@@ -1,11 +1,579 @@
 use crate::user::User;
 use common::manager::{Id, Item, Manager};
+use common::message::{GameResult, GameState, GameSummary, ServerToClient};
+use common::replay::Replay;
+use common::simulation::{Input, Rejection, Tick, Unit, World};
+use common::snapshot::{Snapshot, SnapshotSender};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone)]
+/// How long players have to confirm they are ready once a game is full.
+pub const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long after both players are ready the game starts.
+pub const COUNTDOWN: Duration = Duration::from_secs(3);
+
+/// Messages pushed to users, besides the reply to a request.
+pub type Notifications = Vec<(User, ServerToClient)>;
+
+/// What became of a game after a player or spectator left it.
+pub enum Departure {
+    /// The guest left. The host is still there.
+    GuestLeft { host: User },
+    /// The host left and the guest took over as the host.
+    HostLeft { new_host: User },
+    /// The host left an empty game, which should be closed.
+    Closed,
+    /// The player forfeited the game in progress, which is finished and should be closed.
+    Ended,
+    /// A spectator stopped watching. Nothing changes for the players.
+    StoppedWatching,
+}
+
+/// Why a gameplay input was not applied.
+#[derive(Debug)]
+pub enum InputError {
+    /// The game is not being simulated, because it is not in progress.
+    NotStarted(GameState),
+    /// The input claims a tick the server has not simulated yet.
+    FromTheFuture {
+        client_tick: Tick,
+        tick: Tick,
+    },
+    /// The input is older than one already accepted from the player.
+    OutOfOrder {
+        client_tick: Tick,
+        last: Tick,
+    },
+    Rejected(Rejection),
+}
+
+/// The simulation of a started game. A game worker ticks it while the lobby feeds it the inputs
+/// of the players, so it is shared behind a mutex. It is recorded as it goes, and the recording
+/// saved once the match is dropped.
+pub struct Match {
+    pub game_id: Id,
+    players: Vec<User>,
+    world: World,
+    spectators: Vec<User>,
+    /// Snapshots sent to each player and spectator, by user id.
+    snapshots: HashMap<Id, SnapshotSender>,
+    /// Spectators see the world this many ticks late.
+    delay: Tick,
+    /// The last `delay` states of the world before the current one, oldest first.
+    history: VecDeque<(Tick, Vec<Unit>)>,
+    /// `client_tick` of the last input accepted from each player, by user id.
+    input_ticks: HashMap<Id, Tick>,
+    replay: Replay,
+    /// Where to save `replay`, if anywhere.
+    replay_dir: Option<PathBuf>,
+}
+
+impl Match {
+    fn new(
+        game_id: Id,
+        players: Vec<User>,
+        spectators: Vec<User>,
+        seed: u64,
+        replay_dir: Option<&Path>,
+        delay: Tick,
+    ) -> Self {
+        let ids: Vec<_> = players.iter().map(|player| player.id).collect();
+        let world = World::new(seed, &ids);
+        let snapshots = players
+            .iter()
+            .chain(&spectators)
+            .map(|user| (user.id, SnapshotSender::new()))
+            .collect();
+        Match {
+            game_id,
+            replay: Replay::new(game_id, seed, &ids, &world),
+            replay_dir: replay_dir.map(Path::to_path_buf),
+            world,
+            snapshots,
+            input_ticks: HashMap::new(),
+            players,
+            spectators,
+            delay,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn tick(&self) -> Tick {
+        self.world.tick()
+    }
+
+    /// Advances the simulation by one tick.
+    pub fn step(&mut self) {
+        if self.delay > 0 {
+            let past = (self.world.tick(), self.world.units().to_vec());
+            self.history.push_back(past);
+            if self.history.len() > self.delay as usize {
+                self.history.pop_front();
+            }
+        }
+        self.world.step();
+    }
+
+    /// The state for each player and spectator, relative to what they acknowledged. Spectators
+    /// get nothing until the game is `delay` ticks old.
+    pub fn snapshots(&mut self) -> Vec<(User, Snapshot)> {
+        let tick = self.world.tick();
+        let now = (tick, self.world.units());
+        let delayed = match self.history.front() {
+            _ if self.delay == 0 => Some(now),
+            Some(&(past, ref units)) if past + self.delay == tick => Some((past, &units[..])),
+            _ => None,
+        };
+        let snapshots = &mut self.snapshots;
+        let players = self.players.iter().map(|player| (player, Some(now)));
+        let spectators = self.spectators.iter().map(|spectator| (spectator, delayed));
+        players
+            .chain(spectators)
+            .filter_map(|(user, state)| {
+                let (tick, units) = state?;
+                let sender = snapshots.get_mut(&user.id)?;
+                Some((user.clone(), sender.snapshot(tick, units)))
+            })
+            .collect()
+    }
+}
+
+impl Drop for Match {
+    fn drop(&mut self) {
+        let dir = match self.replay_dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+        self.replay.finish(&self.world);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis());
+        let path = dir.join(format!("game-{}-{}.replay", self.game_id, millis));
+        let result = self
+            .replay
+            .to_bytes()
+            .map_err(|err| format!("{:?}", err))
+            .and_then(|bytes| fs::write(&path, bytes).map_err(|err| err.to_string()));
+        match result {
+            Ok(()) => info!("Recorded game id {} to {}", self.game_id, path.display()),
+            Err(err) => error!("couldn't record {}: {}", path.display(), err),
+        }
+    }
+}
+
+pub type SharedMatch = Arc<Mutex<Match>>;
+
+/// Where a game is in its lifecycle, with what the lobby needs to know in each state.
+#[derive(Clone)]
+enum Phase {
+    WaitingForPlayers,
+    ReadyCheck {
+        /// Players who confirmed, by user id.
+        ready: Vec<Id>,
+        deadline: Instant,
+    },
+    Countdown {
+        deadline: Instant,
+    },
+    /// Simulated by a game worker until the game drops it.
+    InProgress(SharedMatch),
+    /// The simulation has no win condition, so only a forfeit gets a game here.
+    Finished(GameResult),
+    Abandoned,
+}
+
+impl Phase {
+    /// Whether a game may go straight from this phase to `next`.
+    fn leads_to(&self, next: &Phase) -> bool {
+        use self::Phase::*;
+        match (self, next) {
+            (WaitingForPlayers, ReadyCheck { .. }) => true,
+            (ReadyCheck { .. }, Countdown { .. }) => true,
+            (Countdown { .. }, InProgress(_)) => true,
+            (InProgress(_), Finished(_)) => true,
+            // A player left before the game started.
+            (ReadyCheck { .. }, WaitingForPlayers) | (Countdown { .. }, WaitingForPlayers) => true,
+            (Finished(_), _) | (Abandoned, _) => false,
+            (_, Abandoned) => true,
+            _ => false,
+        }
+    }
+
+    /// The state as players see it, at `now`.
+    fn state(&self, now: Instant) -> GameState {
+        let seconds_left = |deadline: Instant| {
+            let left = deadline.saturating_duration_since(now).as_secs_f64().ceil();
+            left.min(f64::from(u32::MAX)) as u32
+        };
+        match *self {
+            Phase::WaitingForPlayers => GameState::WaitingForPlayers,
+            Phase::ReadyCheck { deadline, .. } => GameState::ReadyCheck {
+                seconds: seconds_left(deadline),
+            },
+            Phase::Countdown { deadline } => GameState::Countdown {
+                seconds: seconds_left(deadline),
+            },
+            Phase::InProgress(_) => GameState::InProgress,
+            Phase::Finished(result) => GameState::Finished { result },
+            Phase::Abandoned => GameState::Abandoned,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Game {
     pub id: Id,
     host: User,
     guest: Option<User>,
+    /// Users watching the game, who get its state but have no say in it.
+    spectators: Vec<User>,
+    phase: Phase,
+}
+
+/// Leaves out the units, which would flood the logs.
+impl fmt::Debug for Game {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Game")
+            .field("id", &self.id)
+            .field("host", &self.host)
+            .field("guest", &self.guest)
+            .field("spectators", &self.spectators.len())
+            .field("state", &self.state())
+            .field("tick", &self.lock().map(|running| running.tick()))
+            .finish()
+    }
+}
+
+impl Game {
+    pub fn host(&self) -> &User {
+        &self.host
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.guest.is_some()
+    }
+
+    /// Whether the game waits for a guest, which is what the lobby lists.
+    pub fn is_open(&self) -> bool {
+        matches!(self.phase, Phase::WaitingForPlayers)
+    }
+
+    pub fn state(&self) -> GameState {
+        self.phase.state(Instant::now())
+    }
+
+    pub fn summary(&self) -> GameSummary {
+        GameSummary {
+            game_id: self.id,
+            host_id: self.host.id,
+            players: 1 + self.guest.iter().count(),
+            state: self.state(),
+        }
+    }
+
+    /// Whether the game is being simulated.
+    pub fn is_running(&self) -> bool {
+        matches!(self.phase, Phase::InProgress(_))
+    }
+
+    /// Whether the game is finished or abandoned, and only waits to be closed.
+    pub fn is_over(&self) -> bool {
+        matches!(self.phase, Phase::Finished(_) | Phase::Abandoned)
+    }
+
+    /// When the game moves on by itself, if it waits for the ready check or the countdown.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.phase {
+            Phase::ReadyCheck { deadline, .. } | Phase::Countdown { deadline } => Some(deadline),
+            _ => None,
+        }
+    }
+
+    pub fn has_player(&self, user_id: Id) -> bool {
+        self.host.id == user_id || self.guest.as_ref().map(|guest| guest.id) == Some(user_id)
+    }
+
+    pub fn has_spectator(&self, user_id: Id) -> bool {
+        self.spectators
+            .iter()
+            .any(|spectator| spectator.id == user_id)
+    }
+
+    /// Whether the user plays or watches the game.
+    pub fn has_member(&self, user_id: Id) -> bool {
+        self.has_player(user_id) || self.has_spectator(user_id)
+    }
+
+    /// Everyone in the game, players first.
+    fn members(&self) -> impl Iterator<Item = &User> {
+        ::std::iter::once(&self.host)
+            .chain(&self.guest)
+            .chain(&self.spectators)
+    }
+
+    /// Fails with the state of the game if it may not go to `next`.
+    fn allow(&self, next: &Phase) -> Result<(), GameState> {
+        if self.phase.leads_to(next) {
+            return Ok(());
+        }
+        let state = self.state();
+        error!(
+            "game id {} may not go from {:?} to {:?}",
+            self.id,
+            state,
+            next.state(Instant::now())
+        );
+        Err(state)
+    }
+
+    /// Moves the game to `next` and tells its players and spectators. Fails with the state of the
+    /// game if it may not go there, leaving it as it was.
+    fn enter(&mut self, next: Phase, notifications: &mut Notifications) -> Result<(), GameState> {
+        self.allow(&next)?;
+        self.phase = next;
+        let state = self.state();
+        info!("Game {} is now {:?}", self.id, state);
+        for member in self.members() {
+            let message = ServerToClient::GameStateChanged {
+                game_id: self.id,
+                state,
+            };
+            notifications.push((member.clone(), message));
+        }
+        Ok(())
+    }
+
+    /// Removes the player from the game. Before the game started, the guest is promoted if the
+    /// host leaves and the game waits for players again. Leaving a game in progress forfeits
+    /// it. Spectators just stop watching. Returns `None` if the user was not in this game.
+    pub fn leave(&mut self, user_id: Id, notifications: &mut Notifications) -> Option<Departure> {
+        if self.is_over() {
+            return None;
+        }
+        if self.has_spectator(user_id) {
+            self.spectators.retain(|spectator| spectator.id != user_id);
+            if let Some(mut running) = self.lock() {
+                running
+                    .spectators
+                    .retain(|spectator| spectator.id != user_id);
+                running.snapshots.remove(&user_id);
+            }
+            return Some(Departure::StoppedWatching);
+        }
+        if !self.has_player(user_id) {
+            return None;
+        }
+        if self.is_running() {
+            let winner = if self.host.id == user_id {
+                self.guest.as_ref().expect("a game in progress is full")
+            } else {
+                &self.host
+            };
+            let result = GameResult::Forfeit {
+                winner_id: winner.id,
+            };
+            // A game in progress may always finish.
+            let _ = self.enter(Phase::Finished(result), notifications);
+            return Some(Departure::Ended);
+        }
+
+        let departure = if self.host.id == user_id {
+            match self.guest.take() {
+                Some(guest) => {
+                    self.host = guest;
+                    Departure::HostLeft {
+                        new_host: self.host.clone(),
+                    }
+                }
+                None => return Some(Departure::Closed),
+            }
+        } else {
+            self.guest = None;
+            Departure::GuestLeft {
+                host: self.host.clone(),
+            }
+        };
+        if !matches!(self.phase, Phase::WaitingForPlayers) {
+            // A game that did not start may always wait for players again.
+            let _ = self.enter(Phase::WaitingForPlayers, notifications);
+        }
+        Some(departure)
+    }
+
+    /// Seats `user` as the guest, which starts the ready check. Fails with the state of the game
+    /// if it does not wait for a guest.
+    pub fn join(
+        &mut self,
+        user: &User,
+        notifications: &mut Notifications,
+    ) -> Result<(), GameState> {
+        let ready_check = Phase::ReadyCheck {
+            ready: Vec::new(),
+            deadline: Instant::now() + READY_CHECK_TIMEOUT,
+        };
+        self.allow(&ready_check)?;
+        self.guest = Some(user.clone());
+        self.enter(ready_check, notifications)
+    }
+
+    /// Records that the player is ready, starting the countdown once both are. Fails with the
+    /// state of the game if it is not in the ready check. The caller must have checked that the
+    /// user plays in this game.
+    pub fn ready(
+        &mut self,
+        user_id: Id,
+        notifications: &mut Notifications,
+    ) -> Result<(), GameState> {
+        let state = self.state();
+        let ready = match self.phase {
+            Phase::ReadyCheck { ref mut ready, .. } => ready,
+            _ => return Err(state),
+        };
+        if !ready.contains(&user_id) {
+            ready.push(user_id);
+        }
+        if ready.len() == 2 {
+            let countdown = Phase::Countdown {
+                deadline: Instant::now() + COUNTDOWN,
+            };
+            self.enter(countdown, notifications)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `user` to the spectators. Once the game is in progress, they get the snapshots
+    /// `spectator_delay` ticks late, as given to `start`. The caller must have checked that the
+    /// user is not in this game yet.
+    pub fn spectate(&mut self, user: &User) {
+        debug_assert!(!self.has_member(user.id));
+        self.spectators.push(user.clone());
+        if let Some(mut running) = self.lock() {
+            running.spectators.push(user.clone());
+            running.snapshots.insert(user.id, SnapshotSender::new());
+        }
+    }
+
+    /// Starts simulating the game once its countdown is over, returning the simulation for a game
+    /// worker to tick. It stops once the game drops it, and is recorded into `replay_dir` then.
+    /// Spectators see it `spectator_delay` ticks late. Fails with the state of the game if it
+    /// is not counting down.
+    pub fn start(
+        &mut self,
+        seed: u64,
+        replay_dir: Option<&Path>,
+        spectator_delay: Tick,
+        notifications: &mut Notifications,
+    ) -> Result<SharedMatch, GameState> {
+        // Checked before anything is simulated, since dropping the match would record it.
+        let guest = match (&self.phase, &self.guest) {
+            (Phase::Countdown { .. }, Some(guest)) => guest.clone(),
+            _ => return Err(self.state()),
+        };
+        let players = vec![self.host.clone(), guest];
+        let spectators = self.spectators.clone();
+        let running = Match::new(
+            self.id,
+            players,
+            spectators,
+            seed,
+            replay_dir,
+            spectator_delay,
+        );
+        let running = Arc::new(Mutex::new(running));
+        self.enter(Phase::InProgress(running.clone()), notifications)?;
+        Ok(running)
+    }
+
+    /// Moves the player or spectator to the address and codec of `user`, who reconnected, and
+    /// starts over their snapshots from a full one. The caller must have checked that the user
+    /// is in this game.
+    pub fn rebind(&mut self, user: &User) {
+        let members = ::std::iter::once(&mut self.host)
+            .chain(&mut self.guest)
+            .chain(&mut self.spectators);
+        for member in members {
+            if member.id == user.id {
+                *member = user.clone();
+            }
+        }
+        if let Some(mut running) = self.lock() {
+            let running = &mut *running;
+            for member in running.players.iter_mut().chain(&mut running.spectators) {
+                if member.id == user.id {
+                    *member = user.clone();
+                }
+            }
+            running.snapshots.insert(user.id, SnapshotSender::new());
+        }
+    }
+
+    /// Gives up on the game, whatever state it is in. It should be closed afterwards.
+    pub fn abandon(&mut self, notifications: &mut Notifications) {
+        if !self.is_over() {
+            // Any game that is not over may be abandoned.
+            let _ = self.enter(Phase::Abandoned, notifications);
+        }
+    }
+
+    fn lock(&self) -> Option<MutexGuard<'_, Match>> {
+        match self.phase {
+            Phase::InProgress(ref running) => Some(running.lock().expect("a game worker panicked")),
+            _ => None,
+        }
+    }
+
+    /// Applies an input of the player, who saw the world at `client_tick`. The caller must have
+    /// checked that the user plays in this game.
+    pub fn input(
+        &mut self,
+        user_id: Id,
+        client_tick: Tick,
+        input: Input,
+    ) -> Result<(), InputError> {
+        let state = self.state();
+        let mut running = self.lock().ok_or(InputError::NotStarted(state))?;
+        let running = &mut *running;
+        if running.world.tick() < client_tick {
+            return Err(InputError::FromTheFuture {
+                client_tick,
+                tick: running.world.tick(),
+            });
+        }
+        if let Some(&last) = running.input_ticks.get(&user_id) {
+            if client_tick < last {
+                return Err(InputError::OutOfOrder { client_tick, last });
+            }
+        }
+
+        let tick = running.world.tick();
+        running
+            .world
+            .apply(user_id, input)
+            .map_err(InputError::Rejected)?;
+        running.input_ticks.insert(user_id, client_tick);
+        running.replay.record(tick, user_id, input);
+        Ok(())
+    }
+
+    /// Records that the player holds the snapshot of `tick`. Returns `false` if the game has no
+    /// snapshots for the user.
+    pub fn ack_snapshot(&mut self, user_id: Id, tick: Tick) -> bool {
+        let mut running = match self.lock() {
+            Some(running) => running,
+            None => return false,
+        };
+        match running.snapshots.get_mut(&user_id) {
+            Some(sender) => {
+                sender.ack(tick);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 impl Item<User> for Game {
@@ -14,8 +582,298 @@ impl Item<User> for Game {
             id: *id,
             host: host.clone(),
             guest: None,
+            spectators: Vec::new(),
+            phase: Phase::WaitingForPlayers,
         }
     }
 }
 
 pub type GameManager = Manager<Game, User>;
+
+#[cfg(test)]
+mod test {
+    use super::{Departure, Game, Match, Notifications, Phase, COUNTDOWN, READY_CHECK_TIMEOUT};
+    use crate::user::{User, INITIAL_RATING};
+    use common::codec::Codec;
+    use common::manager::{Id, Item};
+    use common::message::{GameResult, GameState, ServerToClient};
+    use common::simulation::Input;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn user(id: Id) -> User {
+        User {
+            id,
+            addr: ([127, 0, 0, 1], 5000 + id as u16).into(),
+            codec: Codec::Json,
+            token: format!("token {}", id),
+            last_seen: Instant::now(),
+            rating: INITIAL_RATING,
+            away_since: None,
+        }
+    }
+
+    /// A game of host 1 and guest 2, in its ready check.
+    fn joined() -> Game {
+        let mut game = Game::new(&7, &user(1));
+        game.join(&user(2), &mut Notifications::new()).unwrap();
+        game
+    }
+
+    /// A game of host 1 and guest 2, counting down.
+    fn counting_down() -> Game {
+        let mut game = joined();
+        game.ready(1, &mut Notifications::new()).unwrap();
+        game.ready(2, &mut Notifications::new()).unwrap();
+        game
+    }
+
+    /// The users told of each state change, in order.
+    fn changes(notifications: &Notifications) -> Vec<(Id, GameState)> {
+        notifications
+            .iter()
+            .filter_map(|(user, message)| match *message {
+                ServerToClient::GameStateChanged { state, .. } => Some((user.id, state)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spectators_see_the_world_delay_ticks_late() {
+        let mut running = Match::new(7, vec![user(1), user(2)], vec![user(3)], 1, None, 3);
+        running
+            .world
+            .apply(1, Input::MoveTo { dest: (30.0, 7.0) })
+            .unwrap();
+        let mut past = Vec::new();
+        for tick in 0..10 {
+            past.push(running.world.units().to_vec());
+            let snapshots = running.snapshots();
+            let ticks: Vec<_> = snapshots
+                .iter()
+                .map(|(user, snapshot)| (user.id, snapshot.tick))
+                .collect();
+            if tick < 3 {
+                assert_eq!(ticks, vec![(1, tick), (2, tick)]);
+            } else {
+                assert_eq!(ticks, vec![(1, tick), (2, tick), (3, tick - 3)]);
+                assert_eq!(snapshots[2].1.units, past[tick as usize - 3]);
+            }
+            running.step();
+        }
+        assert_eq!(running.history.len(), 3);
+    }
+
+    #[test]
+    fn spectators_see_the_world_live_without_delay() {
+        let mut running = Match::new(7, vec![user(1), user(2)], vec![user(3)], 1, None, 0);
+        for tick in 0..3 {
+            let snapshots = running.snapshots();
+            assert_eq!(snapshots.len(), 3);
+            assert_eq!(snapshots[2].1.tick, tick);
+            assert_eq!(snapshots[2].1.units, running.world.units());
+            running.step();
+        }
+        assert!(running.history.is_empty());
+    }
+
+    #[test]
+    fn phases_lead_only_forward_or_back_to_waiting() {
+        let now = Instant::now();
+        let running = Arc::new(Mutex::new(Match::new(
+            7,
+            vec![user(1), user(2)],
+            Vec::new(),
+            1,
+            None,
+            0,
+        )));
+        let phases = [
+            Phase::WaitingForPlayers,
+            Phase::ReadyCheck {
+                ready: Vec::new(),
+                deadline: now,
+            },
+            Phase::Countdown { deadline: now },
+            Phase::InProgress(running),
+            Phase::Finished(GameResult::Forfeit { winner_id: 1 }),
+            Phase::Abandoned,
+        ];
+        let allowed = [
+            (0, 1),
+            (1, 2),
+            (2, 3),
+            (3, 4),
+            (1, 0),
+            (2, 0),
+            (0, 5),
+            (1, 5),
+            (2, 5),
+            (3, 5),
+        ];
+        for (i, from) in phases.iter().enumerate() {
+            for (j, to) in phases.iter().enumerate() {
+                assert_eq!(
+                    from.leads_to(to),
+                    allowed.contains(&(i, j)),
+                    "{:?} to {:?}",
+                    from.state(now),
+                    to.state(now)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn refused_transitions_leave_the_game_as_it_was() {
+        let mut game = Game::new(&7, &user(1));
+        let mut notifications = Notifications::new();
+        let countdown = Phase::Countdown {
+            deadline: Instant::now(),
+        };
+        assert_eq!(
+            game.enter(countdown, &mut notifications),
+            Err(GameState::WaitingForPlayers)
+        );
+        assert_eq!(game.state(), GameState::WaitingForPlayers);
+        assert!(notifications.is_empty());
+
+        let mut game = joined();
+        assert!(matches!(
+            game.join(&user(3), &mut notifications),
+            Err(GameState::ReadyCheck { .. })
+        ));
+        assert_eq!(game.guest.as_ref().map(|guest| guest.id), Some(2));
+        assert!(game.start(1, None, 0, &mut notifications).is_err());
+        assert!(notifications.is_empty());
+    }
+
+    #[test]
+    fn join_starts_the_ready_check() {
+        let mut game = Game::new(&7, &user(1));
+        let mut notifications = Notifications::new();
+        let before = Instant::now();
+        game.join(&user(2), &mut notifications).unwrap();
+
+        let state = GameState::ReadyCheck { seconds: 30 };
+        assert_eq!(changes(&notifications), vec![(1, state), (2, state)]);
+        let deadline = game.deadline().unwrap();
+        assert!(before + READY_CHECK_TIMEOUT <= deadline);
+        assert!(deadline <= Instant::now() + READY_CHECK_TIMEOUT);
+        assert!(!game.is_open());
+    }
+
+    #[test]
+    fn ready_check_runs_out_at_its_deadline() {
+        let mut game = joined();
+        game.ready(1, &mut Notifications::new()).unwrap();
+        let deadline = game.deadline().unwrap();
+        assert_eq!(
+            game.phase.state(deadline - Duration::from_millis(1500)),
+            GameState::ReadyCheck { seconds: 2 }
+        );
+        assert_eq!(
+            game.phase.state(deadline),
+            GameState::ReadyCheck { seconds: 0 }
+        );
+
+        // What the lobby does once the deadline passed.
+        let mut notifications = Notifications::new();
+        game.abandon(&mut notifications);
+        assert_eq!(
+            changes(&notifications),
+            vec![(1, GameState::Abandoned), (2, GameState::Abandoned)]
+        );
+        assert!(game.is_over());
+        assert_eq!(game.deadline(), None);
+        assert!(game.ready(2, &mut notifications).is_err());
+        assert!(game.start(1, None, 0, &mut notifications).is_err());
+    }
+
+    #[test]
+    fn both_players_ready_start_the_countdown() {
+        let mut game = joined();
+        let mut notifications = Notifications::new();
+        game.ready(1, &mut notifications).unwrap();
+        game.ready(1, &mut notifications).unwrap();
+        assert!(notifications.is_empty());
+
+        let before = Instant::now();
+        game.ready(2, &mut notifications).unwrap();
+        let state = GameState::Countdown { seconds: 3 };
+        assert_eq!(changes(&notifications), vec![(1, state), (2, state)]);
+        let deadline = game.deadline().unwrap();
+        assert!(before + COUNTDOWN <= deadline);
+        assert!(deadline <= Instant::now() + COUNTDOWN);
+        assert_eq!(
+            game.ready(2, &mut notifications),
+            Err(GameState::Countdown { seconds: 3 })
+        );
+    }
+
+    #[test]
+    fn countdown_ends_in_the_game_starting() {
+        let mut game = counting_down();
+        let mut notifications = Notifications::new();
+        let running = game.start(1, None, 0, &mut notifications).unwrap();
+        assert!(game.is_running());
+        assert_eq!(game.deadline(), None);
+        assert_eq!(running.lock().unwrap().players.len(), 2);
+        assert_eq!(
+            changes(&notifications),
+            vec![(1, GameState::InProgress), (2, GameState::InProgress)]
+        );
+        assert!(game.start(1, None, 0, &mut notifications).is_err());
+    }
+
+    #[test]
+    fn leaving_before_the_start_waits_for_players_again() {
+        let mut game = counting_down();
+        let mut notifications = Notifications::new();
+        match game.leave(2, &mut notifications) {
+            Some(Departure::GuestLeft { host }) => assert_eq!(host.id, 1),
+            _ => panic!("the guest should have left"),
+        }
+        assert_eq!(
+            changes(&notifications),
+            vec![(1, GameState::WaitingForPlayers)]
+        );
+        assert!(game.is_open());
+
+        let mut game = joined();
+        match game.leave(1, &mut Notifications::new()) {
+            Some(Departure::HostLeft { new_host }) => assert_eq!(new_host.id, 2),
+            _ => panic!("the host should have left"),
+        }
+        assert_eq!(game.host().id, 2);
+        assert!(game.is_open());
+        assert!(matches!(
+            game.leave(2, &mut Notifications::new()),
+            Some(Departure::Closed)
+        ));
+    }
+
+    #[test]
+    fn leaving_a_game_in_progress_forfeits_it() {
+        let mut game = counting_down();
+        game.start(1, None, 0, &mut Notifications::new()).unwrap();
+        let mut notifications = Notifications::new();
+        assert!(matches!(
+            game.leave(1, &mut notifications),
+            Some(Departure::Ended)
+        ));
+        let state = GameState::Finished {
+            result: GameResult::Forfeit { winner_id: 2 },
+        };
+        assert_eq!(changes(&notifications), vec![(1, state), (2, state)]);
+        assert!(game.is_over());
+        assert!(game.leave(2, &mut notifications).is_none());
+
+        // Abandoning a finished game changes nothing.
+        let mut notifications = Notifications::new();
+        game.abandon(&mut notifications);
+        assert_eq!(game.state(), state);
+        assert!(notifications.is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+//! Flood protection, applied by the network thread before the endpoint processes anything.
+//!
+//! Every source address gets a token bucket: each datagram takes a token and tokens come back at
+//! a steady rate, up to a burst. Datagrams finding the bucket empty are dropped. A host whose
+//! addresses get too many datagrams dropped within `STRIKE_WINDOW` is banned for a while, and
+//! everything it sends is dropped meanwhile.
+
+use crate::config::Config;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Dropped datagrams are counted against a host over this long.
+const STRIKE_WINDOW: Duration = Duration::from_secs(10);
+
+/// What to do with a datagram.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Verdict {
+    Accept,
+    /// The source sends too fast. The datagram is dropped.
+    Throttle,
+    /// The host sent too fast for too long and was banned, just now or earlier.
+    Ban {
+        new: bool,
+    },
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Strikes {
+    count: u32,
+    since: Instant,
+}
+
+pub struct Limiter {
+    rate: f64,
+    burst: f64,
+    ban_threshold: u32,
+    ban_duration: Duration,
+    buckets: HashMap<SocketAddr, Bucket>,
+    strikes: HashMap<IpAddr, Strikes>,
+    /// When each banned host is let back in.
+    bans: HashMap<IpAddr, Instant>,
+}
+
+impl Limiter {
+    pub fn new(config: &Config) -> Self {
+        Limiter {
+            rate: config.rate_limit,
+            burst: f64::from(config.rate_burst),
+            ban_threshold: config.ban_threshold,
+            ban_duration: config.ban_duration,
+            buckets: HashMap::new(),
+            strikes: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    /// Judges a datagram from `addr`, arriving at `now`.
+    pub fn check(&mut self, addr: SocketAddr, now: Instant) -> Verdict {
+        let ip = addr.ip();
+        match self.bans.get(&ip) {
+            Some(&until) if now < until => return Verdict::Ban { new: false },
+            Some(_) => {
+                self.bans.remove(&ip);
+                info!("{} is not banned anymore", ip);
+            }
+            None => {}
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(addr).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Verdict::Accept;
+        }
+
+        let strikes = self.strikes.entry(ip).or_insert(Strikes {
+            count: 0,
+            since: now,
+        });
+        if now.duration_since(strikes.since) >= STRIKE_WINDOW {
+            strikes.count = 0;
+            strikes.since = now;
+        }
+        strikes.count += 1;
+        if strikes.count < self.ban_threshold {
+            return Verdict::Throttle;
+        }
+
+        warn!(
+            "Banned {} for {:?}, it sent {} datagrams over the rate limit",
+            ip, self.ban_duration, strikes.count
+        );
+        self.strikes.remove(&ip);
+        self.buckets.retain(|addr, _| addr.ip() != ip);
+        self.bans.insert(ip, now + self.ban_duration);
+        Verdict::Ban { new: true }
+    }
+
+    /// Forgets about sources that calmed down, so that memory does not grow with every address
+    /// ever seen.
+    pub fn sweep(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+            bucket.tokens + refill < burst
+        });
+        self.strikes
+            .retain(|_, strikes| now.duration_since(strikes.since) < STRIKE_WINDOW);
+        self.bans.retain(|ip, until| {
+            let banned = now < *until;
+            if !banned {
+                info!("{} is not banned anymore", ip);
+            }
+            banned
+        });
+    }
+
+    /// Number of hosts banned right now.
+    pub fn banned(&self) -> usize {
+        self.bans.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Limiter, Verdict, STRIKE_WINDOW};
+    use crate::config::Config;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn limiter() -> Limiter {
+        Limiter::new(&Config {
+            rate_limit: 10.0,
+            rate_burst: 3,
+            ban_threshold: 4,
+            ban_duration: Duration::from_secs(60),
+            ..Config::default()
+        })
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn check_throttles_past_the_burst_until_tokens_come_back() {
+        let now = Instant::now();
+        let mut limiter = limiter();
+        for _ in 0..3 {
+            assert_eq!(limiter.check(addr(1), now), Verdict::Accept);
+        }
+        assert_eq!(limiter.check(addr(1), now), Verdict::Throttle);
+        // Other addresses of the host have their own bucket.
+        assert_eq!(limiter.check(addr(2), now), Verdict::Accept);
+
+        // One token comes back every 100ms.
+        let later = now + Duration::from_millis(150);
+        assert_eq!(limiter.check(addr(1), later), Verdict::Accept);
+        assert_eq!(limiter.check(addr(1), later), Verdict::Throttle);
+    }
+
+    #[test]
+    fn check_bans_the_whole_host_once_it_keeps_going() {
+        let now = Instant::now();
+        let mut limiter = limiter();
+        for _ in 0..3 {
+            limiter.check(addr(1), now);
+        }
+        for _ in 0..3 {
+            assert_eq!(limiter.check(addr(1), now), Verdict::Throttle);
+        }
+        assert_eq!(limiter.check(addr(1), now), Verdict::Ban { new: true });
+        assert_eq!(limiter.banned(), 1);
+        assert_eq!(limiter.check(addr(2), now), Verdict::Ban { new: false });
+        let other = SocketAddr::from(([10, 0, 0, 2], 1));
+        assert_eq!(limiter.check(other, now), Verdict::Accept);
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limiter.check(addr(1), later), Verdict::Accept);
+        assert_eq!(limiter.banned(), 0);
+    }
+
+    #[test]
+    fn check_forgives_strikes_older_than_the_window() {
+        let now = Instant::now();
+        let mut limiter = limiter();
+        for _ in 0..3 {
+            limiter.check(addr(1), now);
+        }
+        for _ in 0..3 {
+            assert_eq!(limiter.check(addr(1), now), Verdict::Throttle);
+        }
+
+        // The bucket refilled meanwhile, so empty it again before striking.
+        let later = now + STRIKE_WINDOW;
+        for _ in 0..3 {
+            assert_eq!(limiter.check(addr(1), later), Verdict::Accept);
+        }
+        for _ in 0..3 {
+            assert_eq!(limiter.check(addr(1), later), Verdict::Throttle);
+        }
+    }
+
+    #[test]
+    fn sweep_forgets_sources_that_calmed_down() {
+        let now = Instant::now();
+        let mut limiter = limiter();
+        limiter.check(addr(1), now);
+        for _ in 0..4 {
+            limiter.check(addr(2), now);
+        }
+        let banned = SocketAddr::from(([10, 0, 0, 2], 1));
+        for _ in 0..7 {
+            limiter.check(banned, now);
+        }
+        assert_eq!(limiter.banned(), 1);
+
+        // The first bucket is full again, the second isn't.
+        limiter.sweep(now + Duration::from_millis(150));
+        assert_eq!(limiter.buckets.len(), 1);
+        assert_eq!(limiter.strikes.len(), 1);
+
+        limiter.sweep(now + STRIKE_WINDOW);
+        assert!(limiter.buckets.is_empty());
+        assert!(limiter.strikes.is_empty());
+        assert_eq!(limiter.banned(), 1);
+
+        limiter.sweep(now + Duration::from_secs(60));
+        assert_eq!(limiter.banned(), 0);
+    }
+}
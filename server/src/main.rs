@@ -1,92 +1,1173 @@
 extern crate common;
+extern crate ctrlc;
 #[macro_use]
 extern crate log;
+extern crate rand;
 
+use common::codec::Codec;
 use common::message::*;
 use common::simple_logger;
+use common::simulation::{Input, Tick, TICKS_PER_SECOND};
+use common::transport::{Endpoint, Event};
+use std::cmp;
+use std::env;
+use std::fs;
 use std::net::SocketAddr;
-use std::net::UdpSocket;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Instant;
 
+mod admin;
+mod config;
 mod game;
+mod limit;
+mod metrics;
+mod net;
+mod queue;
 mod user;
+mod worker;
 
-use crate::game::GameManager;
-use crate::user::UserManager;
+use crate::admin::Command;
+use crate::config::Config;
+use crate::game::{Departure, Game, GameManager, InputError, Notifications};
+use crate::limit::Limiter;
+use crate::metrics::Metrics;
+use crate::net::{Inbound, Outbox};
+use crate::queue::{Queue, PAIRING_INTERVAL};
+use crate::user::{SecureRandom, User, UserManager};
+use crate::worker::Workers;
 
-type CommandResult = Result<ServerToClient, String>;
+/// A failed request, replied to the sender as `ServerToClient::Error`.
+#[derive(Debug)]
+struct CommandError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl CommandError {
+    fn new<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+        CommandError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<CommandError> for ServerToClient {
+    fn from(err: CommandError) -> Self {
+        ServerToClient::Error {
+            code: err.code,
+            message: err.message,
+        }
+    }
+}
+
+/// The reply to a command, if it needs one.
+type CommandResult = Result<Option<ServerToClient>, CommandError>;
+
+/// Everything a command may read or change.
+struct Server {
+    config: Config,
+    random: SecureRandom,
+    user_manager: UserManager,
+    game_manager: GameManager,
+    workers: Workers,
+    /// Users waiting for the server to find them an opponent.
+    queue: Queue,
+    metrics: Arc<Metrics>,
+    /// When games still running will be cut short, once the server was asked to stop.
+    shutdown: Option<Instant>,
+}
+
+/// Exit status when games were still running as the server stopped, at the end of the grace
+/// period or on a second signal. They are abandoned.
+const EXIT_GAMES_CUT_SHORT: i32 = 3;
 
 #[cfg_attr(test, allow(dead_code))]
 fn main() {
-    let _ = simple_logger::init();
+    let args: Vec<_> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", config::USAGE);
+        return;
+    }
+    let config = match Config::from_args(args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            eprintln!();
+            eprintln!("{}", config::USAGE);
+            process::exit(2);
+        }
+    };
+    let _ = simple_logger::init_with_level(config.log_level);
 
-    let addr = ("0.0.0.0", 4567);
-    let socket = match UdpSocket::bind(&addr) {
-        Ok(s) => s,
-        Err(e) => panic!("couldn't bind socket: {}", e),
+    let addr = SocketAddr::new(config.bind, config.port);
+    let endpoint = match Endpoint::bind(addr) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("couldn't bind {}: {}", addr, e);
+            process::exit(1);
+        }
     };
 
     info!("");
     info!("Running \x1b[36m{}\x1b[0m server", common::PROJECT_NAME);
-    info!(
-        "Start listening on \x1b[33m{}:{}\x1b[0m ...",
-        addr.0, addr.1
-    );
+    info!("Start listening on \x1b[33m{}\x1b[0m ...", addr);
     info!("Test it with the command below:");
     info!("");
-    info!("    $ \x1b[1;37mnc -u 127.0.0.1 {}\x1b[0m", addr.1);
+    info!("    $ \x1b[1;37mnc -u 127.0.0.1 {}\x1b[0m", addr.port());
     info!("");
 
-    let mut buf = [0u8; 1024];
-    let mut user_manager = UserManager::new();
-    let mut game_manager = GameManager::new();
-
-    loop {
-        match socket.recv_from(&mut buf) {
-            Ok((amt, src)) => {
-                let buf = &buf[..amt];
-                let msg = String::from_utf8_lossy(buf);
-                let msg = msg[..].trim_end();
-                info!("Received: \x1b[33m\"{}\"\x1b[0m", msg);
-
-                let result = Message::parse(&msg.to_string())
-                    .map_err(|err| format!("{:?} when parsing \"{}\"", err, msg))
-                    .and_then(|command| {
-                        handle_command(&command, &src, &mut user_manager, &mut game_manager)
-                    });
-
-                match result {
-                    Ok(response) => {
-                        let _ = socket.send_to(response.stringify().unwrap().as_bytes(), &src);
+    let random = SecureRandom::new().unwrap_or_else(|e| {
+        error!("couldn't open the random source: {}", e);
+        process::exit(1);
+    });
+    let (inbound, inbox) = mpsc::channel();
+    let signals = inbound.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = signals.send(Inbound::Signal);
+    }) {
+        error!("couldn't handle signals: {}", e);
+        process::exit(1);
+    }
+    if config.admin_port != 0 {
+        match admin::spawn(config.admin_port, inbound.clone()) {
+            Ok(addr) => info!("Admin console on \x1b[33m{}\x1b[0m", addr),
+            Err(e) => {
+                error!("couldn't start the admin console: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    let metrics = Metrics::new();
+    if config.metrics_port != 0 {
+        match metrics::serve(config.metrics_port, metrics.clone()) {
+            Ok(addr) => info!("Metrics on \x1b[33mhttp://{}/metrics\x1b[0m", addr),
+            Err(e) => {
+                error!("couldn't serve the metrics: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(ref dir) = config.replay_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("couldn't create {}: {}", dir.display(), e);
+            process::exit(1);
+        }
+    }
+    let limiter = Limiter::new(&config);
+    let (outbox, network) =
+        net::spawn(endpoint, limiter, inbound, metrics.clone()).unwrap_or_else(|e| {
+            error!("couldn't start the network thread: {}", e);
+            process::exit(1);
+        });
+    let workers = Workers::spawn(config.workers, &outbox, &metrics).unwrap_or_else(|e| {
+        error!("couldn't start the game workers: {}", e);
+        process::exit(1);
+    });
+    let mut server = Server {
+        config,
+        random,
+        user_manager: UserManager::new(),
+        game_manager: GameManager::new(),
+        workers,
+        queue: Queue::new(),
+        metrics,
+        shutdown: None,
+    };
+
+    // The lobby: this thread handles requests, while the workers tick the games.
+    let mut next_sweep = Instant::now() + server.config.sweep_interval;
+    let mut next_pairing = Instant::now() + PAIRING_INTERVAL;
+    let status = loop {
+        let wake = server
+            .game_manager
+            .iter()
+            .filter_map(|game| game.deadline())
+            .chain(server.shutdown)
+            .fold(cmp::min(next_sweep, next_pairing), cmp::min);
+        match inbox.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+            Ok(Inbound::Event(Event::Message {
+                addr: src, payload, ..
+            })) => {
+                server.metrics.inbound_queue.fetch_sub(1, Ordering::Relaxed);
+                let buf = &payload[..];
+                let mut notifications = Notifications::new();
+                let (request_id, result, codec) = match Request::decode(buf) {
+                    Ok((request, codec)) => {
+                        server.metrics.command(request.command.name());
+                        match request.command {
+                            // Sent all the time, so only worth logging when debugging.
+                            ClientToServer::Ping { .. } | ClientToServer::SnapshotAck { .. } => {
+                                debug!("Received: {:?} ({:?})", request.command, codec)
+                            }
+                            _ => info!(
+                                "Received: \x1b[33m{:?}\x1b[0m ({:?})",
+                                request.command, codec
+                            ),
+                        }
+                        // Where a reconnecting user was, so that the endpoint can forget it.
+                        let moved_from = match request.command {
+                            ClientToServer::ReconnectRequest { user_id } => server
+                                .user_manager
+                                .get(user_id)
+                                .map(|user| user.addr)
+                                .filter(|addr| *addr != src),
+                            _ => None,
+                        };
+                        let result =
+                            handle_command(&request, &src, codec, &mut server, &mut notifications);
+                        if let (Some(addr), Ok(_)) = (moved_from, &result) {
+                            if !server.user_manager.iter().any(|user| user.addr == addr) {
+                                outbox.forget(addr);
+                            }
+                        }
+                        (Some(request.id), result, codec)
                     }
                     Err(err) => {
-                        error!("{}", err);
+                        let codec = Codec::detect(buf);
+                        // A client of another version may not get past decoding, but its
+                        // handshake still deserves a proper rejection.
+                        match Handshake::peek(buf) {
+                            Some(ref handshake)
+                                if !is_compatible(
+                                    handshake.protocol_version,
+                                    &handshake.project,
+                                ) =>
+                            {
+                                warn!(
+                                    "Rejected {} speaking \"{}\" protocol version {}",
+                                    src, handshake.project, handshake.protocol_version
+                                );
+                                outbox.reject(handshake, src, codec);
+                                (None, Ok(None), codec)
+                            }
+                            _ => {
+                                server.metrics.parse_failed();
+                                let message = format!(
+                                    "{:?} when parsing \"{}\"",
+                                    err,
+                                    String::from_utf8_lossy(buf).trim_end()
+                                );
+                                let err = CommandError::new(ErrorCode::MalformedMessage, message);
+                                (None, Err(err), codec)
+                            }
+                        }
                     }
+                };
+
+                let response = result.unwrap_or_else(|err| {
+                    error!("{:?}: {}", err.code, err.message);
+                    Some(err.into())
+                });
+                if let Some(response) = response {
+                    outbox.send(request_id, response, src, codec);
+                }
+                for (user, notification) in notifications {
+                    outbox.send(None, notification, user.addr, user.codec);
+                }
+            }
+            Ok(Inbound::Event(Event::Timeout { addr })) => {
+                server.metrics.inbound_queue.fetch_sub(1, Ordering::Relaxed);
+                warn!("{} stopped acknowledging reliable messages", addr);
+                let user_ids = server
+                    .user_manager
+                    .iter()
+                    .filter(|user| user.addr == addr && user.away_since.is_none())
+                    .map(|user| user.id)
+                    .collect();
+                lose(&mut server, &outbox, user_ids);
+            }
+            Ok(Inbound::Signal) if server.shutdown.is_none() => {
+                begin_shutdown(&mut server, &outbox);
+            }
+            Ok(Inbound::Signal) => {
+                warn!("Asked again to stop, stopping now");
+                break EXIT_GAMES_CUT_SHORT;
+            }
+            Ok(Inbound::Admin { command, reply }) => {
+                info!("Admin: \x1b[33m{:?}\x1b[0m", command);
+                let _ = reply.send(administer(&mut server, &outbox, command));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => panic!("the network thread stopped"),
+        }
+
+        advance_games(&mut server, &outbox);
+        server
+            .metrics
+            .set_population(server.user_manager.len(), server.game_manager.len());
+
+        let now = Instant::now();
+        if let Some(end) = server.shutdown {
+            let running = server
+                .game_manager
+                .iter()
+                .filter(|game| game.is_running())
+                .count();
+            if running == 0 {
+                info!("No game is running anymore");
+                break 0;
+            }
+            if end <= now {
+                warn!("Grace period over, abandoning {} running games", running);
+                break EXIT_GAMES_CUT_SHORT;
+            }
+        }
+        if next_sweep <= now {
+            let idle_timeout = server.config.idle_timeout;
+            let user_ids = server
+                .user_manager
+                .iter()
+                .filter(|user| user.away_since.is_none())
+                .filter(|user| now.duration_since(user.last_seen) >= idle_timeout)
+                .map(|user| user.id)
+                .collect();
+            lose(&mut server, &outbox, user_ids);
+
+            let reconnect_window = server.config.reconnect_window;
+            let user_ids = server
+                .user_manager
+                .iter()
+                .filter(|user| {
+                    user.away_since
+                        .is_some_and(|since| now.duration_since(since) >= reconnect_window)
+                })
+                .map(|user| user.id)
+                .collect();
+            evict(
+                &outbox,
+                user_ids,
+                &mut server.user_manager,
+                &mut server.game_manager,
+            );
+            next_sweep = now + server.config.sweep_interval;
+        }
+        if next_pairing <= now {
+            if server.shutdown.is_none() {
+                matchmake(&mut server, &outbox);
+            }
+            next_pairing = now + PAIRING_INTERVAL;
+        }
+    };
+
+    // Players of the games cut short are told before the network thread flushes what is left.
+    abandon_games(&mut server, &outbox, |_| true);
+    // Dropping the games saves their replays. Dropping every outbox then lets the network thread
+    // flush what is left and stop.
+    server.workers.join();
+    drop(server);
+    drop(outbox);
+    if network.join().is_err() {
+        error!("the network thread panicked");
+    }
+    info!("Server stopped");
+    log::logger().flush();
+    process::exit(status);
+}
+
+/// Stops taking new users and games, and tells every user how long the games may go on.
+fn begin_shutdown(server: &mut Server, outbox: &Outbox) {
+    let grace = server.config.shutdown_grace;
+    warn!("Shutting down, giving running games {:?}", grace);
+    server.shutdown = Some(Instant::now() + grace);
+    server.queue.clear();
+
+    // Only games in progress may go on.
+    abandon_games(server, outbox, |game| !game.is_running());
+
+    let grace_seconds = cmp::min(grace.as_secs(), u64::from(u32::MAX)) as u32;
+    for user in server.user_manager.iter() {
+        let message = ServerToClient::ServerShuttingDown {
+            reason: "the server is being stopped".to_string(),
+            grace_seconds,
+        };
+        outbox.send(None, message, user.addr, user.codec);
+    }
+}
+
+/// Abandons and closes the games `filter` picks, telling their players and spectators.
+fn abandon_games<F: Fn(&Game) -> bool>(server: &mut Server, outbox: &Outbox, filter: F) {
+    let game_ids: Vec<_> = server
+        .game_manager
+        .iter()
+        .filter(|game| filter(game))
+        .map(|game| game.id)
+        .collect();
+    let mut notifications = Notifications::new();
+    for game_id in game_ids {
+        if let Some(game) = server.game_manager.get_mut(game_id) {
+            game.abandon(&mut notifications);
+        }
+        server.game_manager.remove(game_id);
+    }
+    for (user, notification) in notifications {
+        outbox.send(None, notification, user.addr, user.codec);
+    }
+}
+
+/// The spectator delay of the settings, in ticks.
+fn spectator_delay(config: &Config) -> Tick {
+    (config.spectator_delay.as_secs_f64() * f64::from(TICKS_PER_SECOND)).round() as Tick
+}
+
+/// Starts the games whose countdown is over, and abandons those whose ready check ran out.
+fn advance_games(server: &mut Server, outbox: &Outbox) {
+    let now = Instant::now();
+    let game_ids: Vec<_> = server
+        .game_manager
+        .iter()
+        .filter(|game| game.deadline().is_some_and(|deadline| deadline <= now))
+        .map(|game| game.id)
+        .collect();
+    let mut notifications = Notifications::new();
+    for game_id in game_ids {
+        let game = match server.game_manager.get_mut(game_id) {
+            Some(game) => game,
+            None => continue,
+        };
+        if let GameState::Countdown { .. } = game.state() {
+            let replay_dir = server.config.replay_dir.as_deref();
+            let delay = spectator_delay(&server.config);
+            let seed = server.random.seed();
+            match game.start(seed, replay_dir, delay, &mut notifications) {
+                Ok(running) => {
+                    server.workers.assign(&running);
+                    continue;
                 }
+                Err(state) => error!("game id {} couldn't start from {:?}", game_id, state),
             }
-            Err(e) => error!("couldn't receive a datagram: {}", e),
+        } else {
+            info!(
+                "Game {} abandoned, its players weren't ready in time",
+                game_id
+            );
         }
+        game.abandon(&mut notifications);
+        server.game_manager.remove(game_id);
+    }
+    for (user, notification) in notifications {
+        outbox.send(None, notification, user.addr, user.codec);
     }
 }
 
-fn handle_command(
-    command: &ClientToServer,
+/// Opens a game for every pair of queued users the queue settles on.
+fn matchmake(server: &mut Server, outbox: &Outbox) {
+    let Server {
+        ref config,
+        ref user_manager,
+        ref mut game_manager,
+        ref mut queue,
+        ..
+    } = *server;
+    // Ids are never reused, so users who left the queue by disconnecting can be found out here.
+    queue.retain(|user_id| user_manager.get(user_id).is_some());
+    let room = config.max_games.saturating_sub(game_manager.len());
+    for (host_id, guest_id) in queue.pair(Instant::now(), room) {
+        let (host, guest) = match (user_manager.get(host_id), user_manager.get(guest_id)) {
+            (Some(host), Some(guest)) => (host, guest),
+            _ => continue,
+        };
+        let game_id = game_manager.create(host).id;
+        let game = game_manager
+            .get_mut(game_id)
+            .expect("the game was just created");
+        let mut notifications = Notifications::new();
+        for player in [host, guest] {
+            let message = ServerToClient::MatchFound {
+                game_id,
+                host_id,
+                guest_id,
+            };
+            notifications.push((User::clone(player), message));
+        }
+        if game.join(guest, &mut notifications).is_err() {
+            game_manager.remove(game_id);
+            continue;
+        }
+        info!(
+            "Matched {:?}, ratings {} and {}",
+            game, host.rating, guest.rating
+        );
+        for (user, notification) in notifications {
+            outbox.send(None, notification, user.addr, user.codec);
+        }
+    }
+}
+
+/// Runs a command of the admin console, returning its answer.
+fn administer(server: &mut Server, outbox: &Outbox, command: Command) -> String {
+    let now = Instant::now();
+    match command {
+        Command::Users => {
+            let mut users: Vec<_> = server.user_manager.iter().collect();
+            users.sort_by_key(|user| user.id);
+            let mut lines = vec![format!("{} users", users.len())];
+            for user in users {
+                let games: Vec<_> = server
+                    .game_manager
+                    .iter()
+                    .filter(|game| game.has_player(user.id))
+                    .map(|game| game.id)
+                    .collect();
+                let away = match user.away_since {
+                    Some(since) => format!("  away {}s", now.duration_since(since).as_secs()),
+                    None => String::new(),
+                };
+                lines.push(format!(
+                    "user {}  {}  {:?}  rating {}  idle {}s{}  games {:?}{}",
+                    user.id,
+                    user.addr,
+                    user.codec,
+                    user.rating,
+                    now.duration_since(user.last_seen).as_secs(),
+                    away,
+                    games,
+                    if server.queue.contains(user.id) {
+                        "  queued"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+            lines.join("\n")
+        }
+        Command::Games => {
+            let mut games: Vec<_> = server.game_manager.iter().collect();
+            games.sort_by_key(|game| game.id);
+            let mut lines = vec![format!("{} games", games.len())];
+            for game in games {
+                lines.push(format!("{:?}", game));
+            }
+            lines.join("\n")
+        }
+        Command::Kick(user_id) => {
+            if server.user_manager.get(user_id).is_none() {
+                return format!("user id {} does not exist", user_id);
+            }
+            evict(
+                outbox,
+                vec![user_id],
+                &mut server.user_manager,
+                &mut server.game_manager,
+            );
+            format!("kicked user {}", user_id)
+        }
+        Command::Close(game_id) => {
+            let game = match server.game_manager.get_mut(game_id) {
+                Some(game) => game,
+                None => return format!("game id {} does not exist", game_id),
+            };
+            let mut notifications = Notifications::new();
+            game.abandon(&mut notifications);
+            server.game_manager.remove(game_id);
+            info!("Game {} closed by an admin", game_id);
+            for (user, notification) in notifications {
+                outbox.send(None, notification, user.addr, user.codec);
+            }
+            format!("closed game {}", game_id)
+        }
+        Command::Rate(user_id, rating) => match server.user_manager.get_mut(user_id) {
+            Some(user) => {
+                user.rating = rating;
+                format!("user {} is now rated {}", user_id, rating)
+            }
+            None => format!("user id {} does not exist", user_id),
+        },
+        Command::Broadcast(text) => {
+            for user in server.user_manager.iter() {
+                let message = ServerToClient::Announcement { text: text.clone() };
+                outbox.send(None, message, user.addr, user.codec);
+            }
+            format!("sent to {} users", server.user_manager.len())
+        }
+        Command::LogLevel(level) => {
+            log::set_max_level(level);
+            format!("log level is now {}", level)
+        }
+        Command::Help => admin::HELP.to_string(),
+    }
+}
+
+/// Deals with users who went silent. Players and spectators keep their games for the reconnect
+/// window, everyone else is evicted right away.
+fn lose(server: &mut Server, outbox: &Outbox, user_ids: Vec<usize>) {
+    let now = Instant::now();
+    let mut gone = Vec::new();
+    for user_id in user_ids {
+        let playing = server
+            .game_manager
+            .iter()
+            .any(|game| game.has_member(user_id));
+        match server.user_manager.get_mut(user_id) {
+            Some(user) if playing => {
+                info!(
+                    "User {} is away, keeping their games for {:?}",
+                    user_id, server.config.reconnect_window
+                );
+                user.away_since = Some(now);
+            }
+            Some(_) => gone.push(user_id),
+            None => {}
+        }
+    }
+    evict(
+        outbox,
+        gone,
+        &mut server.user_manager,
+        &mut server.game_manager,
+    );
+}
+
+/// Disconnects users that went away without saying so, and tells the players they left behind.
+fn evict(
+    outbox: &Outbox,
+    user_ids: Vec<usize>,
+    user_manager: &mut UserManager,
+    game_manager: &mut GameManager,
+) {
+    let mut notifications = Notifications::new();
+    for user_id in user_ids {
+        let addr = match user_manager.get(user_id) {
+            Some(user) => user.addr,
+            None => continue,
+        };
+        if let Err(err) = disconnect(user_manager, game_manager, user_id, &mut notifications) {
+            error!("{:?}: {}", err.code, err.message);
+        }
+        info!("User {} evicted", user_id);
+        if !user_manager.iter().any(|user| user.addr == addr) {
+            outbox.forget(addr);
+        }
+    }
+
+    // Players evicted together must not be told about each other.
+    for (user, notification) in notifications {
+        if user_manager.get(user.id).is_some() {
+            outbox.send(None, notification, user.addr, user.codec);
+        }
+    }
+}
+
+/// Finds the user a request acts as, making sure it carries the user's session token and comes
+/// from the user's address. The user is then known to be alive.
+fn authorize<'a>(
+    user_manager: &'a mut UserManager,
+    user_id: usize,
+    request: &Request,
     src: &SocketAddr,
+) -> Result<&'a User, CommandError> {
+    let user = user_manager.get_mut(user_id).ok_or_else(|| {
+        CommandError::new(
+            ErrorCode::UnknownUser,
+            format!("user id {} does not exist", user_id),
+        )
+    })?;
+    if !user.authorizes(request.token.as_ref(), src) {
+        return Err(CommandError::new(
+            ErrorCode::NotAuthorized,
+            format!("{} may not act as user id {}", src, user_id),
+        ));
+    }
+    user.last_seen = Instant::now();
+    user.away_since = None;
+    Ok(user)
+}
+
+/// Removes the user from the game, closing it if nobody is left or it ended, and telling the
+/// remaining player.
+fn leave_game(
+    game_manager: &mut GameManager,
+    game_id: usize,
+    user_id: usize,
+    notifications: &mut Notifications,
+) -> Result<(), CommandError> {
+    let game = game_manager.get_mut(game_id).ok_or_else(|| {
+        CommandError::new(
+            ErrorCode::UnknownGame,
+            format!("game id {} does not exist", game_id),
+        )
+    })?;
+    // The departure is told before the state change it causes.
+    let mut changes = Notifications::new();
+    let departure = game.leave(user_id, &mut changes).ok_or_else(|| {
+        CommandError::new(
+            ErrorCode::InvalidRequest,
+            format!("user id {} is not in game id {}", user_id, game_id),
+        )
+    })?;
+
+    match departure {
+        Departure::GuestLeft { host } => {
+            info!("User {} left {:?}", user_id, game);
+            notifications.push((host, ServerToClient::GuestLeft { game_id, user_id }));
+        }
+        Departure::HostLeft { new_host } => {
+            info!("User {} left {:?}", user_id, game);
+            let host_id = new_host.id;
+            notifications.push((new_host, ServerToClient::HostChanged { game_id, host_id }));
+        }
+        Departure::Closed => {
+            game_manager.remove(game_id);
+            info!("Game {} closed", game_id);
+        }
+        Departure::Ended => {
+            game_manager.remove(game_id);
+            info!("Game {} closed, user {} forfeited it", game_id, user_id);
+        }
+        Departure::StoppedWatching => {
+            info!("User {} stopped watching game {}", user_id, game_id);
+        }
+    }
+    notifications.extend(changes);
+    Ok(())
+}
+
+/// Takes the user out of every game, then forgets about the user.
+fn disconnect(
     user_manager: &mut UserManager,
     game_manager: &mut GameManager,
+    user_id: usize,
+    notifications: &mut Notifications,
+) -> Result<(), CommandError> {
+    let game_ids: Vec<_> = game_manager
+        .iter()
+        .filter(|game| game.has_member(user_id))
+        .map(|game| game.id)
+        .collect();
+    for game_id in game_ids {
+        leave_game(game_manager, game_id, user_id, notifications)?;
+    }
+    user_manager.remove(user_id);
+    Ok(())
+}
+
+/// Feeds a gameplay input of the user to the simulation of the game.
+fn play(
+    game_manager: &mut GameManager,
+    game_id: usize,
+    user_id: usize,
+    client_tick: Tick,
+    input: Input,
+) -> CommandResult {
+    let game = game_manager.get_mut(game_id).ok_or_else(|| {
+        CommandError::new(
+            ErrorCode::UnknownGame,
+            format!("game id {} does not exist", game_id),
+        )
+    })?;
+    if game.has_spectator(user_id) {
+        return Err(CommandError::new(
+            ErrorCode::NotAuthorized,
+            format!("user id {} only watches game id {}", user_id, game_id),
+        ));
+    }
+    if !game.has_player(user_id) {
+        return Err(CommandError::new(
+            ErrorCode::InvalidRequest,
+            format!("user id {} is not in game id {}", user_id, game_id),
+        ));
+    }
+    game.input(user_id, client_tick, input)
+        .map_err(|err| match err {
+            InputError::NotStarted(state) => CommandError::new(
+                ErrorCode::WrongGameState,
+                format!("game id {} is not in progress but {:?}", game_id, state),
+            ),
+            InputError::FromTheFuture { client_tick, tick } => CommandError::new(
+                ErrorCode::InputRejected,
+                format!("client tick {} is ahead of tick {}", client_tick, tick),
+            ),
+            InputError::OutOfOrder { client_tick, last } => CommandError::new(
+                ErrorCode::InputRejected,
+                format!(
+                    "client tick {} is older than client tick {}",
+                    client_tick, last
+                ),
+            ),
+            InputError::Rejected(rejection) => CommandError::new(
+                ErrorCode::InputRejected,
+                format!("{:?} in game id {}", rejection, game_id),
+            ),
+        })?;
+    Ok(None)
+}
+
+fn handle_command(
+    request: &Request,
+    src: &SocketAddr,
+    codec: Codec,
+    server: &mut Server,
+    notifications: &mut Notifications,
 ) -> CommandResult {
-    match *command {
-        ClientToServer::ConnectRequest => {
-            let user = user_manager.create(src);
+    let Server {
+        ref config,
+        ref mut random,
+        ref mut user_manager,
+        ref mut game_manager,
+        ref mut queue,
+        ref metrics,
+        shutdown,
+        ..
+    } = *server;
+    let refuse_if_shutting_down = || match shutdown {
+        Some(_) => Err(CommandError::new(
+            ErrorCode::ShuttingDown,
+            "the server is shutting down",
+        )),
+        None => Ok(()),
+    };
+    match request.command {
+        ClientToServer::ConnectRequest {
+            protocol_version,
+            ref project,
+        } => {
+            if !is_compatible(protocol_version, project) {
+                warn!(
+                    "Rejected {} speaking \"{}\" protocol version {}",
+                    src, project, protocol_version
+                );
+                return Ok(Some(ServerToClient::ConnectRejected {
+                    project: common::PROJECT_NAME.to_string(),
+                    min_version: MIN_PROTOCOL_VERSION,
+                    max_version: PROTOCOL_VERSION,
+                }));
+            }
+
+            refuse_if_shutting_down()?;
+            if user_manager.len() >= config.max_users {
+                warn!("Refused {}: {} users connected", src, user_manager.len());
+                return Err(CommandError::new(
+                    ErrorCode::CapacityReached,
+                    "the server is full",
+                ));
+            }
+            let from_ip = user_manager
+                .iter()
+                .filter(|user| user.addr.ip() == src.ip())
+                .count();
+            if from_ip >= config.max_users_per_ip {
+                warn!("Refused {}: {} users connected from its IP", src, from_ip);
+                metrics.ip_refused();
+                return Err(CommandError::new(
+                    ErrorCode::CapacityReached,
+                    format!("{} users are connected from {} already", from_ip, src.ip()),
+                ));
+            }
+
+            let user = user_manager.create(&(*src, codec, random.token()));
             info!("{:?} created", user);
-            Ok(ServerToClient::ConnectResponse { user_id: user.id })
-        }
-        ClientToServer::CreateGameRequest { user_id } => user_manager
-            .get(user_id)
-            .ok_or(format!("user id {} is not exists", user_id))
-            .map(|user| {
-                let game = game_manager.create(&user);
-                info!("{:?} created", game);
-                ServerToClient::CreateGameResponse { game_id: game.id }
-            }),
+            Ok(Some(ServerToClient::ConnectResponse {
+                user_id: user.id,
+                token: user.token.clone(),
+            }))
+        }
+        ClientToServer::ReconnectRequest { user_id } => {
+            let user = user_manager.get_mut(user_id).ok_or_else(|| {
+                CommandError::new(
+                    ErrorCode::UnknownUser,
+                    format!("user id {} does not exist", user_id),
+                )
+            })?;
+            if !user.holds(request.token.as_ref()) {
+                return Err(CommandError::new(
+                    ErrorCode::NotAuthorized,
+                    format!("{} may not act as user id {}", src, user_id),
+                ));
+            }
+            info!("User {} reconnected from {} to {}", user_id, user.addr, src);
+            user.addr = *src;
+            user.codec = codec;
+            user.last_seen = Instant::now();
+            user.away_since = None;
+            let user = user.clone();
+
+            let mut game_ids = Vec::new();
+            for game in game_manager.iter_mut() {
+                if !game.has_member(user_id) {
+                    continue;
+                }
+                game.rebind(&user);
+                let message = ServerToClient::GameStateChanged {
+                    game_id: game.id,
+                    state: game.state(),
+                };
+                notifications.push((user.clone(), message));
+                game_ids.push(game.id);
+            }
+            game_ids.sort_unstable();
+            Ok(Some(ServerToClient::ReconnectResponse {
+                user_id,
+                game_ids,
+            }))
+        }
+        ClientToServer::CreateGameRequest { user_id } => {
+            let user = authorize(user_manager, user_id, request, src)?;
+            refuse_if_shutting_down()?;
+            if game_manager.len() >= config.max_games {
+                return Err(CommandError::new(
+                    ErrorCode::CapacityReached,
+                    format!("{} games are open already", game_manager.len()),
+                ));
+            }
+            if queue.leave(user_id) {
+                info!("User {} left the queue to host a game", user_id);
+            }
+            let game = game_manager.create(user);
+            info!("{:?} created", game);
+            Ok(Some(ServerToClient::CreateGameResponse {
+                game_id: game.id,
+            }))
+        }
+        ClientToServer::JoinGameRequest { user_id, game_id } => {
+            let user = authorize(user_manager, user_id, request, src)?;
+            refuse_if_shutting_down()?;
+            let game = game_manager.get_mut(game_id).ok_or_else(|| {
+                CommandError::new(
+                    ErrorCode::UnknownGame,
+                    format!("game id {} does not exist", game_id),
+                )
+            })?;
+            if game.host().id == user_id {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("user id {} already hosts game id {}", user_id, game_id),
+                ));
+            }
+            if game.has_spectator(user_id) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("user id {} watches game id {}", user_id, game_id),
+                ));
+            }
+            if game.is_full() {
+                return Err(CommandError::new(
+                    ErrorCode::CapacityReached,
+                    format!("game id {} is full", game_id),
+                ));
+            }
+
+            // The host hears of the guest before the ready check starts.
+            let joined = notifications.len();
+            game.join(user, notifications).map_err(|state| {
+                CommandError::new(
+                    ErrorCode::WrongGameState,
+                    format!("game id {} is {:?}", game_id, state),
+                )
+            })?;
+            notifications.insert(
+                joined,
+                (
+                    game.host().clone(),
+                    ServerToClient::GuestJoined { game_id, user_id },
+                ),
+            );
+            info!("{:?} joined", game);
+            if queue.leave(user_id) {
+                info!("User {} left the queue to join a game", user_id);
+            }
+            Ok(Some(ServerToClient::JoinGameResponse {
+                game_id,
+                host_id: game.host().id,
+            }))
+        }
+        ClientToServer::LeaveGameRequest { user_id, game_id } => {
+            authorize(user_manager, user_id, request, src)?;
+            leave_game(game_manager, game_id, user_id, notifications)?;
+            Ok(Some(ServerToClient::LeaveGameResponse { game_id }))
+        }
+        ClientToServer::DisconnectRequest { user_id } => {
+            authorize(user_manager, user_id, request, src)?;
+            disconnect(user_manager, game_manager, user_id, notifications)?;
+            info!("User {} disconnected", user_id);
+            Ok(Some(ServerToClient::DisconnectResponse))
+        }
+        ClientToServer::Ping { user_id } => {
+            authorize(user_manager, user_id, request, src)?;
+            Ok(Some(ServerToClient::Pong))
+        }
+        ClientToServer::SnapshotAck {
+            user_id,
+            game_id,
+            tick,
+        } => {
+            authorize(user_manager, user_id, request, src)?;
+            let game = game_manager.get_mut(game_id).ok_or_else(|| {
+                CommandError::new(
+                    ErrorCode::UnknownGame,
+                    format!("game id {} does not exist", game_id),
+                )
+            })?;
+            if !game.is_running() {
+                return Err(CommandError::new(
+                    ErrorCode::WrongGameState,
+                    format!(
+                        "game id {} is not in progress but {:?}",
+                        game_id,
+                        game.state()
+                    ),
+                ));
+            }
+            if !game.ack_snapshot(user_id, tick) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "user id {} gets no snapshots of game id {}",
+                        user_id, game_id
+                    ),
+                ));
+            }
+            Ok(None)
+        }
+        ClientToServer::ListGamesRequest { page } => {
+            let mut games: Vec<_> = game_manager
+                .iter()
+                .filter(|game| game.is_open())
+                .map(|game| game.summary())
+                .collect();
+            games.sort_by_key(|game| game.game_id);
+
+            let page_count = games.len().div_ceil(GAMES_PER_PAGE);
+            let games = games
+                .into_iter()
+                .skip(page.saturating_mul(GAMES_PER_PAGE))
+                .take(GAMES_PER_PAGE)
+                .collect();
+            Ok(Some(ServerToClient::GameList {
+                page,
+                page_count,
+                games,
+            }))
+        }
+        ClientToServer::MoveTo {
+            user_id,
+            game_id,
+            client_tick,
+            dest,
+        } => {
+            authorize(user_manager, user_id, request, src)?;
+            play(
+                game_manager,
+                game_id,
+                user_id,
+                client_tick,
+                Input::MoveTo { dest },
+            )
+        }
+        ClientToServer::CastSkill {
+            user_id,
+            game_id,
+            client_tick,
+            slot,
+            target,
+        } => {
+            authorize(user_manager, user_id, request, src)?;
+            let input = Input::CastSkill { slot, target };
+            play(game_manager, game_id, user_id, client_tick, input)
+        }
+        ClientToServer::EnterQueue { user_id } => {
+            let user = authorize(user_manager, user_id, request, src)?;
+            refuse_if_shutting_down()?;
+            let playing = game_manager
+                .iter()
+                .find(|game| game.has_player(user_id) && !game.is_over());
+            if let Some(game) = playing {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("user id {} plays in game id {}", user_id, game.id),
+                ));
+            }
+            if !queue.enter(user_id, user.rating, Instant::now()) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("user id {} is queued already", user_id),
+                ));
+            }
+            info!(
+                "User {} entered the queue, {} waiting",
+                user_id,
+                queue.len()
+            );
+            Ok(Some(ServerToClient::EnterQueueResponse {
+                rating: user.rating,
+            }))
+        }
+        ClientToServer::LeaveQueue { user_id } => {
+            authorize(user_manager, user_id, request, src)?;
+            if !queue.leave(user_id) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("user id {} is not queued", user_id),
+                ));
+            }
+            info!("User {} left the queue", user_id);
+            Ok(Some(ServerToClient::LeaveQueueResponse))
+        }
+        ClientToServer::Ready { user_id, game_id } => {
+            authorize(user_manager, user_id, request, src)?;
+            let game = game_manager.get_mut(game_id).ok_or_else(|| {
+                CommandError::new(
+                    ErrorCode::UnknownGame,
+                    format!("game id {} does not exist", game_id),
+                )
+            })?;
+            if !game.has_player(user_id) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("user id {} is not in game id {}", user_id, game_id),
+                ));
+            }
+            game.ready(user_id, notifications).map_err(|state| {
+                CommandError::new(
+                    ErrorCode::WrongGameState,
+                    format!("game id {} has no ready check but is {:?}", game_id, state),
+                )
+            })?;
+            Ok(None)
+        }
+        ClientToServer::Stop {
+            user_id,
+            game_id,
+            client_tick,
+        } => {
+            authorize(user_manager, user_id, request, src)?;
+            play(game_manager, game_id, user_id, client_tick, Input::Stop)
+        }
+        ClientToServer::SpectateRequest { user_id, game_id } => {
+            let user = authorize(user_manager, user_id, request, src)?;
+            refuse_if_shutting_down()?;
+            let game = game_manager.get_mut(game_id).ok_or_else(|| {
+                CommandError::new(
+                    ErrorCode::UnknownGame,
+                    format!("game id {} does not exist", game_id),
+                )
+            })?;
+            if game.has_member(user_id) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("user id {} is in game id {} already", user_id, game_id),
+                ));
+            }
+            if game.is_over() {
+                return Err(CommandError::new(
+                    ErrorCode::WrongGameState,
+                    format!("game id {} is {:?}", game_id, game.state()),
+                ));
+            }
+
+            game.spectate(user);
+            info!("User {} watches {:?}", user_id, game);
+            let message = ServerToClient::GameStateChanged {
+                game_id,
+                state: game.state(),
+            };
+            notifications.push((user.clone(), message));
+            Ok(Some(ServerToClient::SpectateResponse {
+                game_id,
+                delay_ticks: spectator_delay(config),
+            }))
+        }
     }
 }
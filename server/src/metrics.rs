@@ -0,0 +1,280 @@
+//! Counters and gauges about the running server, served in the Prometheus text format on a
+//! loopback HTTP port. Every thread updates them directly, so they are atomics shared in an `Arc`.
+
+use common::transport::Stats;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Metrics {
+    datagrams_received: AtomicU64,
+    datagrams_sent: AtomicU64,
+    parse_failures: AtomicU64,
+    throttled: AtomicU64,
+    bans: AtomicU64,
+    banned: AtomicUsize,
+    ip_refusals: AtomicU64,
+    /// Requests handled, by variant name.
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+    users: AtomicUsize,
+    games: AtomicUsize,
+    ticks: AtomicU64,
+    tick_nanos: AtomicU64,
+    /// Network events waiting for the lobby.
+    pub inbound_queue: AtomicUsize,
+    /// Messages waiting for the network thread.
+    pub outbound_queue: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Metrics::default())
+    }
+
+    pub fn set_datagrams(&self, stats: Stats) {
+        self.datagrams_received
+            .store(stats.datagrams_received, Ordering::Relaxed);
+        self.datagrams_sent
+            .store(stats.datagrams_sent, Ordering::Relaxed);
+    }
+
+    pub fn parse_failed(&self) {
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a datagram dropped for going over the rate limit, or coming from a banned host.
+    pub fn throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_banned(&self, new_bans: u64, banned: usize) {
+        self.bans.fetch_add(new_bans, Ordering::Relaxed);
+        self.banned.store(banned, Ordering::Relaxed);
+    }
+
+    /// Records a connection refused because its IP address has too many users already.
+    pub fn ip_refused(&self) {
+        self.ip_refusals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command(&self, name: &'static str) {
+        let mut commands = self.commands.lock().expect("a thread panicked");
+        *commands.entry(name).or_insert(0) += 1;
+    }
+
+    pub fn set_population(&self, users: usize, games: usize) {
+        self.users.store(users, Ordering::Relaxed);
+        self.games.store(games, Ordering::Relaxed);
+    }
+
+    /// Records how long a game worker took to tick its games once.
+    pub fn tick(&self, duration: Duration) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.tick_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Every metric, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn ToString| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.to_string());
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let size = |gauge: &AtomicUsize| gauge.load(Ordering::Relaxed);
+
+        metric(
+            "fate_datagrams_received_total",
+            "counter",
+            "UDP datagrams received, acks and retransmissions included.",
+            &load(&self.datagrams_received),
+        );
+        metric(
+            "fate_datagrams_sent_total",
+            "counter",
+            "UDP datagrams sent, acks and retransmissions included.",
+            &load(&self.datagrams_sent),
+        );
+        metric(
+            "fate_parse_failures_total",
+            "counter",
+            "Messages that could not be decoded as a request.",
+            &load(&self.parse_failures),
+        );
+        metric(
+            "fate_throttled_total",
+            "counter",
+            "Datagrams dropped for going over the rate limit or coming from a banned host.",
+            &load(&self.throttled),
+        );
+        metric(
+            "fate_bans_total",
+            "counter",
+            "Hosts banned for flooding.",
+            &load(&self.bans),
+        );
+        metric(
+            "fate_banned_hosts",
+            "gauge",
+            "Hosts banned right now.",
+            &size(&self.banned),
+        );
+        metric(
+            "fate_ip_refusals_total",
+            "counter",
+            "Connections refused because their IP address had too many users.",
+            &load(&self.ip_refusals),
+        );
+        metric(
+            "fate_users",
+            "gauge",
+            "Users connected.",
+            &size(&self.users),
+        );
+        metric("fate_games", "gauge", "Games open.", &size(&self.games));
+        metric(
+            "fate_inbound_queue_depth",
+            "gauge",
+            "Network events waiting for the lobby.",
+            &size(&self.inbound_queue),
+        );
+        metric(
+            "fate_outbound_queue_depth",
+            "gauge",
+            "Messages waiting for the network thread.",
+            &size(&self.outbound_queue),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP fate_tick_duration_seconds Time a game worker took to tick its games once."
+        );
+        let _ = writeln!(out, "# TYPE fate_tick_duration_seconds summary");
+        let seconds = load(&self.tick_nanos) as f64 / 1e9;
+        let _ = writeln!(out, "fate_tick_duration_seconds_sum {}", seconds);
+        let _ = writeln!(
+            out,
+            "fate_tick_duration_seconds_count {}",
+            load(&self.ticks)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP fate_commands_total Requests handled, by command."
+        );
+        let _ = writeln!(out, "# TYPE fate_commands_total counter");
+        for (name, count) in self.commands.lock().expect("a thread panicked").iter() {
+            let _ = writeln!(out, "fate_commands_total{{command=\"{}\"}} {}", name, count);
+        }
+        out
+    }
+}
+
+/// Serves the metrics over HTTP on the loopback `port`, to one scraper at a time.
+pub fn serve(port: u16, metrics: Arc<Metrics>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let addr = listener.local_addr()?;
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| respond(stream, &metrics));
+                if let Err(e) = result {
+                    warn!("couldn't serve the metrics: {}", e);
+                }
+            }
+        })?;
+    Ok(addr)
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    // The headers don't matter, but must be read before answering.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use common::transport::Stats;
+    use std::time::Duration;
+
+    #[test]
+    fn render_every_metric() {
+        let metrics = Metrics::new();
+        metrics.set_datagrams(Stats {
+            datagrams_received: 12,
+            datagrams_sent: 34,
+        });
+        metrics.parse_failed();
+        metrics.throttled();
+        metrics.throttled();
+        metrics.set_banned(1, 1);
+        metrics.set_population(5, 2);
+        metrics.tick(Duration::from_millis(250));
+        metrics.tick(Duration::from_millis(250));
+        metrics.command("Ping");
+        metrics.command("Ping");
+        metrics.command("CreateGameRequest");
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        for line in &[
+            "fate_datagrams_received_total 12",
+            "fate_datagrams_sent_total 34",
+            "fate_parse_failures_total 1",
+            "fate_throttled_total 2",
+            "fate_bans_total 1",
+            "fate_banned_hosts 1",
+            "fate_ip_refusals_total 0",
+            "fate_users 5",
+            "fate_games 2",
+            "# TYPE fate_tick_duration_seconds summary",
+            "fate_tick_duration_seconds_sum 0.5",
+            "fate_tick_duration_seconds_count 2",
+            "fate_commands_total{command=\"CreateGameRequest\"} 1",
+            "fate_commands_total{command=\"Ping\"} 2",
+        ] {
+            assert!(lines.contains(line), "`{}` is missing from\n{}", line, text);
+        }
+
+        // Every sample is announced by its HELP and TYPE lines.
+        for (i, line) in lines.iter().enumerate() {
+            if line.starts_with("# HELP ") {
+                assert!(lines[i + 1].starts_with("# TYPE "), "{}", line);
+            }
+        }
+        assert_eq!(
+            lines.iter().filter(|l| l.starts_with("# HELP")).count(),
+            lines.iter().filter(|l| l.starts_with("# TYPE")).count()
+        );
+    }
+}
@@ -0,0 +1,221 @@
+//! The network thread. It owns the endpoint, so it alone touches the socket: it turns datagrams
+//! into events on the inbound queue and sends what the other threads put on the outbound queue.
+//! Once the outbound queue is closed, it lingers until the peers acked everything, so that the
+//! last messages of a shutdown are not lost.
+
+use crate::admin::Command;
+use crate::limit::{Limiter, Verdict};
+use crate::metrics::Metrics;
+use common::codec::Codec;
+use common::message::{Handshake, Message, RequestId, Response, ServerToClient};
+use common::message::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use common::transport::{Channel, Endpoint, Event};
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long the network thread waits for a datagram before looking at the outbound queue again.
+/// It bounds the latency the queue adds to every message.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// How often the limiter forgets sources that calmed down.
+const LIMITER_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long unacked messages are retransmitted once the outbound queue is closed.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What the lobby waits for.
+pub enum Inbound {
+    Event(Event),
+    /// The process got SIGINT or SIGTERM.
+    Signal,
+    /// A command from the admin console, whose answer goes to `reply`.
+    Admin {
+        command: Command,
+        reply: Sender<String>,
+    },
+}
+
+enum Outbound {
+    Send {
+        addr: SocketAddr,
+        channel: Channel,
+        payload: Vec<u8>,
+    },
+    Forget(SocketAddr),
+}
+
+/// The sending end of the outbound queue. Messages are encoded by the thread sending them.
+#[derive(Clone)]
+pub struct Outbox {
+    queue: Sender<Outbound>,
+    metrics: Arc<Metrics>,
+}
+
+impl Outbox {
+    /// Sends a message to a client. Snapshots go on the unreliable channel, since each one
+    /// supersedes the previous one. Everything else is a lobby message that must not be lost.
+    pub fn send(
+        &self,
+        request_id: Option<RequestId>,
+        message: ServerToClient,
+        addr: SocketAddr,
+        codec: Codec,
+    ) {
+        let channel = match message {
+            ServerToClient::Snapshot { .. } => Channel::Unreliable,
+            _ => Channel::Reliable,
+        };
+        let response = Response {
+            request_id,
+            message,
+        };
+        self.push_encoded(&response, channel, addr, codec);
+    }
+
+    /// Tells a client whose handshake couldn't be decoded as a `Request` which protocol versions
+    /// the server speaks. Versions that predate `Response` get the rejection bare.
+    pub fn reject(&self, handshake: &Handshake, addr: SocketAddr, codec: Codec) {
+        let message = ServerToClient::ConnectRejected {
+            project: common::PROJECT_NAME.to_string(),
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        };
+        match handshake.request_id {
+            Some(request_id) => self.send(Some(request_id), message, addr, codec),
+            None => self.push_encoded(&message, Channel::Reliable, addr, codec),
+        }
+    }
+
+    fn push_encoded<M: Message + Debug>(
+        &self,
+        message: &M,
+        channel: Channel,
+        addr: SocketAddr,
+        codec: Codec,
+    ) {
+        match Message::encode(message, codec) {
+            Ok(payload) => self.push(Outbound::Send {
+                addr,
+                channel,
+                payload,
+            }),
+            Err(err) => error!("{:?} when encoding {:?}", err, message),
+        }
+    }
+
+    /// Drops everything the endpoint knows about the peer.
+    pub fn forget(&self, addr: SocketAddr) {
+        self.push(Outbound::Forget(addr));
+    }
+
+    fn push(&self, outbound: Outbound) {
+        self.metrics.outbound_queue.fetch_add(1, Ordering::Relaxed);
+        if self.queue.send(outbound).is_err() {
+            self.metrics.outbound_queue.fetch_sub(1, Ordering::Relaxed);
+            error!("the network thread is gone");
+        }
+    }
+}
+
+/// Starts the network thread, which puts events on `inbound` from the datagrams `limiter` lets
+/// through. It stops once every `Outbox` is dropped and what they sent is flushed, or once the
+/// inbound queue is dropped.
+pub fn spawn(
+    endpoint: Endpoint,
+    limiter: Limiter,
+    inbound: Sender<Inbound>,
+    metrics: Arc<Metrics>,
+) -> io::Result<(Outbox, JoinHandle<()>)> {
+    let (queue, outbound) = mpsc::channel();
+    let outbox = Outbox {
+        queue,
+        metrics: metrics.clone(),
+    };
+    let handle = thread::Builder::new()
+        .name("network".to_string())
+        .spawn(move || run(endpoint, limiter, &inbound, &outbound, &metrics))?;
+    Ok((outbox, handle))
+}
+
+fn run(
+    mut endpoint: Endpoint,
+    mut limiter: Limiter,
+    inbound: &Sender<Inbound>,
+    outbound: &Receiver<Outbound>,
+    metrics: &Metrics,
+) {
+    let mut next_sweep = Instant::now() + LIMITER_SWEEP_INTERVAL;
+    loop {
+        loop {
+            let next = outbound.try_recv();
+            if next.is_ok() {
+                metrics.outbound_queue.fetch_sub(1, Ordering::Relaxed);
+            }
+            match next {
+                Ok(Outbound::Send {
+                    addr,
+                    channel,
+                    payload,
+                }) => {
+                    if let Err(e) = endpoint.send(addr, channel, &payload) {
+                        error!("couldn't send to {}: {:?}", addr, e);
+                    }
+                }
+                Ok(Outbound::Forget(addr)) => endpoint.forget(&addr),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return flush(&mut endpoint),
+            }
+        }
+
+        let mut filter = |addr| accept(&mut limiter, addr, metrics);
+        match endpoint.recv_filtered(Some(POLL_INTERVAL), &mut filter) {
+            Ok(Some(event)) => {
+                metrics.inbound_queue.fetch_add(1, Ordering::Relaxed);
+                if inbound.send(Inbound::Event(event)).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => error!("couldn't receive a datagram: {}", e),
+        }
+        metrics.set_datagrams(endpoint.stats());
+
+        let now = Instant::now();
+        if next_sweep <= now {
+            limiter.sweep(now);
+            metrics.set_banned(0, limiter.banned());
+            next_sweep = now + LIMITER_SWEEP_INTERVAL;
+        }
+    }
+}
+
+/// Whether a datagram from `addr` may reach the endpoint, which acks it and passes it on to the
+/// lobby.
+fn accept(limiter: &mut Limiter, addr: SocketAddr, metrics: &Metrics) -> bool {
+    match limiter.check(addr, Instant::now()) {
+        Verdict::Accept => return true,
+        Verdict::Throttle => debug!("dropped a datagram from {}, over the rate limit", addr),
+        Verdict::Ban { new } => metrics.set_banned(u64::from(new), limiter.banned()),
+    }
+    metrics.throttled();
+    false
+}
+
+fn flush(endpoint: &mut Endpoint) {
+    let deadline = Instant::now() + FLUSH_TIMEOUT;
+    while !endpoint.all_acked() && Instant::now() < deadline {
+        // Nobody listens anymore, but receiving is what processes acks and retransmits.
+        if let Err(e) = endpoint.recv(Some(POLL_INTERVAL)) {
+            error!("couldn't receive a datagram: {}", e);
+        }
+    }
+    if !endpoint.all_acked() {
+        warn!("some peers never acked the last messages");
+    }
+}
@@ -0,0 +1,170 @@
+//! Matchmaking. Users waiting for an opponent are paired by rating, only with close ratings at
+//! first and with wider gaps the longer they wait, so that nobody waits forever.
+
+use common::manager::Id;
+use std::time::{Duration, Instant};
+
+/// How often the lobby pairs the users waiting.
+pub const PAIRING_INTERVAL: Duration = Duration::from_millis(500);
+/// Rating gap accepted as soon as a user enters the queue.
+const INITIAL_SPREAD: f64 = 100.0;
+/// Rating gap accepted on top of `INITIAL_SPREAD` for every second waited.
+const SPREAD_PER_SECOND: f64 = 25.0;
+
+struct Entry {
+    user_id: Id,
+    rating: u32,
+    since: Instant,
+}
+
+/// Users waiting for an opponent, longest waiting first.
+#[derive(Default)]
+pub struct Queue {
+    entries: Vec<Entry>,
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Queue::default()
+    }
+
+    /// Puts the user at the end of the queue. Returns `false` if the user waits already.
+    pub fn enter(&mut self, user_id: Id, rating: u32, now: Instant) -> bool {
+        if self.contains(user_id) {
+            return false;
+        }
+        self.entries.push(Entry {
+            user_id,
+            rating,
+            since: now,
+        });
+        true
+    }
+
+    /// Takes the user out of the queue. Returns `false` if the user was not waiting.
+    pub fn leave(&mut self, user_id: Id) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.user_id != user_id);
+        self.entries.len() != len
+    }
+
+    pub fn contains(&self, user_id: Id) -> bool {
+        self.entries.iter().any(|entry| entry.user_id == user_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Keeps only the users for which `f` returns `true`.
+    pub fn retain<F: FnMut(Id) -> bool>(&mut self, mut f: F) {
+        self.entries.retain(|entry| f(entry.user_id));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Pairs up to `max` couples of waiting users and takes them out of the queue. Each pair is
+    /// `(host, guest)`, the host being the one who waited longer.
+    ///
+    /// The users who waited longest are served first, each with the closest rating within the
+    /// gap their wait allows.
+    pub fn pair(&mut self, now: Instant, max: usize) -> Vec<(Id, Id)> {
+        let entries = &self.entries;
+        let mut paired = vec![false; entries.len()];
+        let mut pairs = Vec::new();
+        for (i, host) in entries.iter().enumerate() {
+            if pairs.len() >= max {
+                break;
+            }
+            if paired[i] {
+                continue;
+            }
+            // Everyone after `host` waited less, so the gap `host` accepts is the widest.
+            let waited = now.saturating_duration_since(host.since).as_secs_f64();
+            let spread = INITIAL_SPREAD + SPREAD_PER_SECOND * waited;
+            let guest = (i + 1..entries.len())
+                .filter(|&j| !paired[j])
+                .map(|j| (j, host.rating.abs_diff(entries[j].rating)))
+                .filter(|&(_, gap)| f64::from(gap) <= spread)
+                .min_by_key(|&(_, gap)| gap);
+            if let Some((j, _)) = guest {
+                paired[i] = true;
+                paired[j] = true;
+                pairs.push((host.user_id, entries[j].user_id));
+            }
+        }
+
+        let mut paired = paired.into_iter();
+        self.entries.retain(|_| !paired.next().unwrap_or(false));
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Queue;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn enter_and_leave_once() {
+        let now = Instant::now();
+        let mut queue = Queue::new();
+        assert!(queue.enter(1, 1500, now));
+        assert!(!queue.enter(1, 1500, now));
+        assert!(queue.contains(1));
+        assert!(queue.leave(1));
+        assert!(!queue.leave(1));
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn pair_serves_the_longest_waiting_with_the_closest_rating() {
+        let now = Instant::now();
+        let mut queue = Queue::new();
+        queue.enter(1, 1500, now);
+        queue.enter(2, 1580, now);
+        queue.enter(3, 1520, now);
+        queue.enter(4, 1600, now);
+
+        assert_eq!(queue.pair(now, 10), vec![(1, 3), (2, 4)]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn pair_widens_the_rating_gap_with_the_wait() {
+        let start = Instant::now();
+        let mut queue = Queue::new();
+        queue.enter(1, 1500, start);
+        queue.enter(2, 1700, start);
+        assert!(queue.pair(start, 10).is_empty());
+
+        // 100 at first, and 25 more per second waited.
+        assert!(queue.pair(start + Duration::from_secs(3), 10).is_empty());
+        assert_eq!(queue.pair(start + Duration::from_secs(4), 10), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn pair_uses_the_gap_of_whoever_waited_longer() {
+        let start = Instant::now();
+        let mut queue = Queue::new();
+        queue.enter(1, 1500, start);
+        let later = start + Duration::from_secs(4);
+        queue.enter(2, 1700, later);
+        assert_eq!(queue.pair(later, 10), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn pair_stops_at_max_and_keeps_the_rest_in_order() {
+        let now = Instant::now();
+        let mut queue = Queue::new();
+        for user_id in 1..=5 {
+            queue.enter(user_id, 1500, now);
+        }
+        assert_eq!(queue.pair(now, 1), vec![(1, 2)]);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pair(now, 10), vec![(3, 4)]);
+        assert!(queue.contains(5));
+    }
+}
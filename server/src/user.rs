@@ -1,19 +1,98 @@
+use common::codec::Codec;
 use common::manager::{Id, Item, Manager};
+use common::message::SessionToken;
+use rand::{OsRng, Rng};
+use std::fmt;
 use std::net::SocketAddr;
+use std::time::Instant;
 
-#[derive(Debug, Clone)]
+/// Rating of a user who has not played yet.
+pub const INITIAL_RATING: u32 = 1500;
+
+#[derive(Clone)]
 pub struct User {
     pub id: Id,
-    addr: SocketAddr,
+    pub addr: SocketAddr,
+    /// Codec the user's client spoke on connect. Pushed messages are encoded with it.
+    pub codec: Codec,
+    pub token: SessionToken,
+    /// When the last authorized request of the user arrived.
+    pub last_seen: Instant,
+    /// Skill estimate the matchmaking queue pairs users by.
+    pub rating: u32,
+    /// Since when the user is gone silent while playing. The user keeps their games for the
+    /// reconnect window, from where they were or from a new address.
+    pub away_since: Option<Instant>,
+}
+
+/// Leaves out the token, which must not end up in logs.
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("addr", &self.addr)
+            .field("codec", &self.codec)
+            .field("rating", &self.rating)
+            .finish()
+    }
+}
+
+impl User {
+    /// Whether a request carrying `token` and coming from `addr` may act as this user.
+    pub fn authorizes(&self, token: Option<&SessionToken>, addr: &SocketAddr) -> bool {
+        self.holds(token) && self.addr == *addr
+    }
+
+    /// Whether `token` is the session token of this user, wherever the request came from.
+    pub fn holds(&self, token: Option<&SessionToken>) -> bool {
+        let token = match token {
+            Some(token) => token.as_bytes(),
+            None => return false,
+        };
+        // Compares every byte, so that the time taken tells nothing about the correct prefix.
+        let expected = self.token.as_bytes();
+        let difference = expected
+            .iter()
+            .zip(token)
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        expected.len() == token.len() && difference == 0
+    }
 }
 
-impl Item<SocketAddr> for User {
-    fn new(id: &Id, addr: &SocketAddr) -> Self {
+impl Item<(SocketAddr, Codec, SessionToken)> for User {
+    fn new(id: &Id, &(addr, codec, ref token): &(SocketAddr, Codec, SessionToken)) -> Self {
         User {
             id: *id,
-            addr: *addr,
+            addr,
+            codec,
+            token: token.clone(),
+            last_seen: Instant::now(),
+            rating: INITIAL_RATING,
+            away_since: None,
         }
     }
 }
 
-pub type UserManager = Manager<User, SocketAddr>;
+pub type UserManager = Manager<User, (SocketAddr, Codec, SessionToken)>;
+
+/// Draws session tokens and simulation seeds from the operating system's random source.
+pub struct SecureRandom {
+    rng: OsRng,
+}
+
+impl SecureRandom {
+    pub fn new() -> ::std::io::Result<Self> {
+        Ok(SecureRandom { rng: OsRng::new()? })
+    }
+
+    /// A 128 bit session token.
+    pub fn token(&mut self) -> SessionToken {
+        let mut bytes = [0u8; 16];
+        self.rng.fill_bytes(&mut bytes);
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn seed(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+}
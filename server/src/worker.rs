@@ -0,0 +1,102 @@
+//! Game workers. Each one ticks the matches handed to it on its own clock and sends their
+//! snapshots, so a busy worker or a busy lobby does not hold up the other games.
+
+use crate::game::{Match, SharedMatch};
+use crate::metrics::Metrics;
+use crate::net::Outbox;
+use common::message::ServerToClient;
+use common::simulation::TICK_INTERVAL;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+pub struct Workers {
+    queues: Vec<Sender<Weak<Mutex<Match>>>>,
+    next: usize,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Starts `count` worker threads sending snapshots to `outbox`.
+    pub fn spawn(count: usize, outbox: &Outbox, metrics: &Arc<Metrics>) -> io::Result<Self> {
+        let mut queues = Vec::with_capacity(count);
+        let mut threads = Vec::with_capacity(count);
+        for i in 0..count {
+            let (queue, inbox) = mpsc::channel();
+            let outbox = outbox.clone();
+            let metrics = metrics.clone();
+            let thread = thread::Builder::new()
+                .name(format!("game worker {}", i))
+                .spawn(move || run(&inbox, &outbox, &metrics))?;
+            queues.push(queue);
+            threads.push(thread);
+        }
+        Ok(Workers {
+            queues,
+            next: 0,
+            threads,
+        })
+    }
+
+    /// Hands a started match to a worker, which ticks it until every other `Arc` to it is gone.
+    pub fn assign(&mut self, running: &SharedMatch) {
+        let queue = &self.queues[self.next];
+        self.next = (self.next + 1) % self.queues.len();
+        if queue.send(Arc::downgrade(running)).is_err() {
+            error!("a game worker is gone");
+        }
+    }
+
+    /// Stops every worker and waits for them, so that none holds a match anymore. No match can be
+    /// assigned afterwards.
+    pub fn join(&mut self) {
+        self.queues.clear();
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("a game worker panicked");
+            }
+        }
+    }
+}
+
+fn run(inbox: &Receiver<Weak<Mutex<Match>>>, outbox: &Outbox, metrics: &Metrics) {
+    let mut matches: Vec<Weak<Mutex<Match>>> = Vec::new();
+    let mut next_tick = Instant::now() + TICK_INTERVAL;
+    loop {
+        match inbox.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+            Ok(running) => matches.push(running),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        if next_tick > now {
+            continue;
+        }
+        let idle = matches.is_empty();
+        matches.retain(|running| {
+            let running = match running.upgrade() {
+                Some(running) => running,
+                None => return false,
+            };
+            let mut running = running.lock().expect("the lobby panicked");
+            running.step();
+            let game_id = running.game_id;
+            for (user, snapshot) in running.snapshots() {
+                let message = ServerToClient::Snapshot { game_id, snapshot };
+                outbox.send(None, message, user.addr, user.codec);
+            }
+            true
+        });
+        if !idle {
+            metrics.tick(now.elapsed());
+        }
+        next_tick += TICK_INTERVAL;
+        if next_tick < now {
+            warn!("simulation fell behind by {:?}", now - next_tick);
+            next_tick = now + TICK_INTERVAL;
+        }
+    }
+}
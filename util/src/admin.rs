@@ -0,0 +1,44 @@
+//! Admin mode: a prompt for the admin console of a server.
+
+use std::io::{self, stdin, stdout, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+
+/// Sends every line typed to the console and prints its answers, until stdin or the connection
+/// closes.
+pub fn run(addr: SocketAddr) -> io::Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    let mut writer = stream.try_clone()?;
+    let mut answers = BufReader::new(stream).lines();
+    println!("Connected to the admin console of {}, try `help`.", addr);
+
+    let stdin = stdin();
+    loop {
+        print!("> ");
+        stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        writer.write_all(line.as_bytes())?;
+
+        // An answer ends with an empty line.
+        loop {
+            match answers.next() {
+                Some(answer) => {
+                    let answer = answer?;
+                    if answer.is_empty() {
+                        break;
+                    }
+                    println!("{}", answer);
+                }
+                None => {
+                    println!("The server closed the console.");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -1,88 +1,320 @@
 extern crate common;
 
-use common::message::{ClientToServer, Message, ServerToClient};
+mod admin;
+
+use common::codec::Codec;
+use common::message::{ClientToServer, Message, Request, Response, ServerToClient, SessionToken};
+use common::pending::PendingRequests;
+use common::simulation::Tick;
+use common::transport::{Channel, Endpoint, Event};
 use std::env;
 use std::io::stdin;
 use std::io::stdout;
 use std::io::Result as IoResult;
 use std::io::Write;
-use std::net::UdpSocket;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: commander [options]
+
+Options:
+    --server <host:port>     Server to talk to [default: 127.0.0.1:4567]
+    --bind <address:port>    Local address, port 0 picks a free one [default: 0.0.0.0:0]
+    --codec <json|binary>    Encoding of messages [default: json]
+    --admin <host:port>      Open the admin console of a server instead, like 127.0.0.1:4568
+    --help                   Show this message";
+
+/// Prints the problem with the command line and exits.
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    eprintln!();
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
 
 fn main() {
-    let mut args = env::args();
-    let _program_name = args.next();
-    let ip = args.next().unwrap_or_else(|| "127.0.0.1".to_string());
-    let port: u16 = args
-        .next()
-        .unwrap_or_else(|| "4567".to_string())
-        .parse()
-        .unwrap();
+    let mut server = "127.0.0.1:4567".to_string();
+    let mut bind = "0.0.0.0:0".to_string();
+    let mut codec = Codec::Json;
+    let mut admin = None;
 
-    let addr = ("0.0.0.0", 7654);
-    let target = (&ip[..], port);
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        if flag == "--help" || flag == "-h" {
+            println!("{}", USAGE);
+            return;
+        }
+        let value = args
+            .next()
+            .unwrap_or_else(|| usage_error(&format!("`{}` needs a value", flag)));
+        match &flag[..] {
+            "--server" => server = value,
+            "--bind" => bind = value,
+            "--admin" => admin = Some(value),
+            "--codec" => {
+                codec = match &value[..] {
+                    "json" => Codec::Json,
+                    "binary" => Codec::Binary,
+                    _ => usage_error(&format!("unknown codec `{}`", value)),
+                }
+            }
+            _ => usage_error(&format!("unknown flag `{}`", flag)),
+        }
+    }
 
-    let socket = UdpSocket::bind(&addr).unwrap_or_else(|e| {
-        panic!("couldn't bind socket: {}", e);
+    if let Some(admin) = admin {
+        let addr = match admin.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            _ => usage_error(&format!("couldn't resolve `{}`", admin)),
+        };
+        if let Err(e) = admin::run(addr) {
+            eprintln!("admin console failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let target = match server.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(target)) => target,
+        _ => usage_error(&format!("couldn't resolve `{}`", server)),
+    };
+    let addr: SocketAddr = bind
+        .parse()
+        .unwrap_or_else(|_| usage_error(&format!("invalid address `{}`", bind)));
+
+    let mut endpoint = Endpoint::bind(addr).unwrap_or_else(|e| {
+        eprintln!("couldn't bind {}: {}", addr, e);
+        process::exit(1);
     });
 
-    let mut buf = [0u8; 1024];
+    let mut pending = PendingRequests::new(Duration::from_secs(5));
+    let mut token: Option<SessionToken> = None;
+    // Latest snapshot tick seen, which stamps gameplay inputs.
+    let mut tick: Tick = 0;
     loop {
-        let command = command_to_send().unwrap();
-        let _ = socket.send_to(command.stringify().unwrap().as_bytes(), &target);
-        match socket.recv_from(&mut buf) {
-            Ok((amt, _)) => {
-                let buf = &buf[..amt];
-                let msg = String::from_utf8_lossy(buf);
-                let msg = msg[..].trim_end();
-                println!("Received: \x1b[33m\"{}\"\x1b[0m", msg);
-
-                let result = Message::parse(&msg.to_string())
-                    .map_err(|err| format!("{:?} when parsing \"{}\"", err, msg))
-                    .map(|command: ServerToClient| format!("{:?}", command));
-
-                match result {
-                    Ok(result) => {
-                        println!("{}", result);
+        let command = command_to_send(tick).unwrap();
+        if let ClientToServer::ReconnectRequest { .. } = command {
+            // Pretend the network changed, like the server would see it.
+            let moved = SocketAddr::new(addr.ip(), 0);
+            endpoint = Endpoint::bind(moved).unwrap_or_else(|e| {
+                eprintln!("couldn't bind {}: {}", moved, e);
+                process::exit(1);
+            });
+        }
+        let expects_reply = !matches!(
+            command,
+            ClientToServer::SnapshotAck { .. }
+                | ClientToServer::MoveTo { .. }
+                | ClientToServer::CastSkill { .. }
+                | ClientToServer::Stop { .. }
+                | ClientToServer::Ready { .. }
+        );
+        let id = pending.start(format!("{:?}", command), Instant::now());
+        let request = Request {
+            id,
+            token: token.clone(),
+            command,
+        };
+        let buf = request.encode(codec).unwrap();
+        if let Err(e) = endpoint.send(target, Channel::Reliable, &buf) {
+            println!("couldn't send the request: {:?}", e);
+            pending.complete(id);
+            continue;
+        }
+
+        // Commands without a reply are only answered when they fail, so give a failure a moment.
+        let mut quiet_until = Instant::now();
+        if !expects_reply {
+            pending.complete(id);
+            quiet_until += Duration::from_millis(300);
+        }
+
+        // Print everything the server sends until our request is answered or expires.
+        while !pending.is_empty() || Instant::now() < quiet_until {
+            let deadline = pending.next_deadline().unwrap_or(quiet_until);
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match endpoint.recv(Some(timeout)) {
+                Ok(Some(Event::Message { payload, .. })) => {
+                    let response = match print_response(&payload) {
+                        Some(response) => response,
+                        None => continue,
+                    };
+                    match response.message {
+                        ServerToClient::ConnectResponse { token: ref t, .. } => {
+                            token = Some(t.clone());
+                        }
+                        ServerToClient::Snapshot { ref snapshot, .. } => {
+                            tick = ::std::cmp::max(tick, snapshot.tick);
+                        }
+                        _ => {}
                     }
-                    Err(err) => {
-                        println!("{}", err);
+                    if let Some(request_id) = response.request_id {
+                        pending.complete(request_id);
                     }
                 }
+                Ok(Some(Event::Timeout { .. })) => {
+                    println!("The server doesn't acknowledge our messages.");
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    println!("couldn't receive a datagram: {}", e);
+                }
             }
-            Err(e) => {
-                println!("couldn't receive a datagram: {}", e);
+            for (_, command) in pending.expire(Instant::now()) {
+                println!("No response to {}.", command);
             }
         }
     }
 }
 
-fn command_to_send() -> IoResult<ClientToServer> {
+/// Prints a datagram from the server, returning it decoded. Snapshots are returned unprinted.
+fn print_response(buf: &[u8]) -> Option<Response> {
+    let decoded = Response::decode(buf);
+    if let Ok((
+        Response {
+            message: ServerToClient::Snapshot { .. },
+            ..
+        },
+        _,
+    )) = decoded
+    {
+        // Snapshots stream in at the tick rate, far too often to print.
+        return decoded.ok().map(|(response, _)| response);
+    }
+
+    if Codec::detect(buf) == Codec::Json {
+        let msg = String::from_utf8_lossy(buf);
+        println!("Received: \x1b[33m\"{}\"\x1b[0m", msg.trim_end());
+    } else {
+        println!("Received: \x1b[33m{} bytes\x1b[0m", buf.len());
+    }
+
+    let response = match decoded {
+        Ok((response, _)) => response,
+        Err(err) => {
+            println!("{:?} when parsing the datagram", err);
+            return None;
+        }
+    };
+    let message = match response.message {
+        ServerToClient::Error { code, ref message } => {
+            format!("\x1b[31mError {:?}\x1b[0m: {}", code, message)
+        }
+        ref message => format!("{:?}", message),
+    };
+    match response.request_id {
+        Some(id) => println!("#{} {}", id, message),
+        None => println!("Pushed {}", message),
+    }
+    Some(response)
+}
+
+fn command_to_send(tick: Tick) -> IoResult<ClientToServer> {
     println!("=============");
     println!("1: ConnectRequest");
     println!("2: CreateGameRequest");
+    println!("3: JoinGameRequest");
+    println!("4: ListGamesRequest");
+    println!("5: LeaveGameRequest");
+    println!("6: DisconnectRequest");
+    println!("7: Ping");
+    println!("8: MoveTo");
+    println!("9: CastSkill");
+    println!("10: Stop");
+    println!("11: EnterQueue");
+    println!("12: LeaveQueue");
+    println!("13: Ready");
+    println!("14: ReconnectRequest, from a new local port");
+    println!("15: SpectateRequest");
     loop {
         let mut line = String::new();
         let _len = stdin().read_line(&mut line)?;
         let line = line.trim();
         match line {
-            "1" => return Ok(ClientToServer::ConnectRequest),
+            "1" => return Ok(ClientToServer::connect_request()),
             "2" => {
-                print!("Enter user id: (or enter to cancel)");
-                let _ = stdout().flush();
-                loop {
-                    let mut line = String::new();
-                    let _len = stdin().read_line(&mut line)?;
-                    let line = line.trim();
-                    if line == "" {
-                        continue;
-                    }
-                    match line.parse::<usize>() {
-                        Ok(user_id) => return Ok(ClientToServer::CreateGameRequest { user_id }),
-                        Err(_) => {
-                            println!("Invalid user id.");
-                        }
-                    }
-                }
+                let user_id = read_id("user")?;
+                return Ok(ClientToServer::CreateGameRequest { user_id });
+            }
+            "3" => {
+                let user_id = read_id("user")?;
+                let game_id = read_id("game")?;
+                return Ok(ClientToServer::JoinGameRequest { user_id, game_id });
+            }
+            "4" => {
+                let page = read_number("page")?;
+                return Ok(ClientToServer::ListGamesRequest { page });
+            }
+            "5" => {
+                let user_id = read_id("user")?;
+                let game_id = read_id("game")?;
+                return Ok(ClientToServer::LeaveGameRequest { user_id, game_id });
+            }
+            "6" => {
+                let user_id = read_id("user")?;
+                return Ok(ClientToServer::DisconnectRequest { user_id });
+            }
+            "7" => {
+                let user_id = read_id("user")?;
+                return Ok(ClientToServer::Ping { user_id });
+            }
+            "8" => {
+                let user_id = read_id("user")?;
+                let game_id = read_id("game")?;
+                let x = read_float("x")?;
+                let y = read_float("y")?;
+                return Ok(ClientToServer::MoveTo {
+                    user_id,
+                    game_id,
+                    client_tick: tick,
+                    dest: (x, y),
+                });
+            }
+            "9" => {
+                let user_id = read_id("user")?;
+                let game_id = read_id("game")?;
+                let slot = read_number("slot")?;
+                return Ok(ClientToServer::CastSkill {
+                    user_id,
+                    game_id,
+                    client_tick: tick,
+                    slot,
+                    target: (0.0, 0.0),
+                });
+            }
+            "10" => {
+                let user_id = read_id("user")?;
+                let game_id = read_id("game")?;
+                return Ok(ClientToServer::Stop {
+                    user_id,
+                    game_id,
+                    client_tick: tick,
+                });
+            }
+            "11" => {
+                let user_id = read_id("user")?;
+                return Ok(ClientToServer::EnterQueue { user_id });
+            }
+            "12" => {
+                let user_id = read_id("user")?;
+                return Ok(ClientToServer::LeaveQueue { user_id });
+            }
+            "13" => {
+                let user_id = read_id("user")?;
+                let game_id = read_id("game")?;
+                return Ok(ClientToServer::Ready { user_id, game_id });
+            }
+            "14" => {
+                let user_id = read_id("user")?;
+                return Ok(ClientToServer::ReconnectRequest { user_id });
+            }
+            "15" => {
+                let user_id = read_id("user")?;
+                let game_id = read_id("game")?;
+                return Ok(ClientToServer::SpectateRequest { user_id, game_id });
             }
             _ => {
                 println!("Invalid input");
@@ -90,3 +322,45 @@ fn command_to_send() -> IoResult<ClientToServer> {
         }
     }
 }
+
+fn read_id(name: &str) -> IoResult<usize> {
+    read_number(&format!("{} id", name))
+}
+
+fn read_number<T: FromStr>(name: &str) -> IoResult<T> {
+    print!("Enter {}: ", name);
+    let _ = stdout().flush();
+    loop {
+        let mut line = String::new();
+        let _len = stdin().read_line(&mut line)?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse::<T>() {
+            Ok(number) => return Ok(number),
+            Err(_) => {
+                println!("Invalid {}.", name);
+            }
+        }
+    }
+}
+
+fn read_float(name: &str) -> IoResult<f32> {
+    print!("Enter {}: ", name);
+    let _ = stdout().flush();
+    loop {
+        let mut line = String::new();
+        let _len = stdin().read_line(&mut line)?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse::<f32>() {
+            Ok(value) => return Ok(value),
+            Err(_) => {
+                println!("Invalid {}.", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+extern crate common;
+
+use common::replay::{Divergence, Error, Replay};
+use std::env;
+use std::fs;
+use std::process;
+
+const USAGE: &str = "\
+Usage: verify-replay <file>...
+
+Re-simulates each match recorded by the server and checks that it ends the way it was recorded.
+
+Exit status:
+    0  every replay reproduces
+    1  a replay could not be read or does not reproduce
+    2  invalid options";
+
+fn describe(replay: &Replay, divergence: &Divergence) -> String {
+    match *divergence {
+        Divergence::Initial => "the seed and players spawn different units".to_string(),
+        Divergence::Rejected {
+            index,
+            ref rejection,
+        } => {
+            let recorded = &replay.inputs[index];
+            format!(
+                "input #{} of user {} at tick {} is refused: {:?}",
+                index, recorded.player, recorded.tick, rejection
+            )
+        }
+        Divergence::InputOutOfRange { index } => format!(
+            "input #{} is recorded at tick {}, out of order or past the end",
+            index, replay.inputs[index].tick
+        ),
+        Divergence::End => format!("the units differ at tick {}", replay.end_tick),
+    }
+}
+
+/// Checks one file, returning what went wrong.
+fn verify(path: &str) -> Result<Replay, String> {
+    let bytes = fs::read(path).map_err(|err| format!("couldn't read it: {}", err))?;
+    let replay = Replay::from_bytes(&bytes).map_err(|err| match err {
+        Error::NotAReplay => "not a replay".to_string(),
+        Error::UnsupportedVersion(version) => format!("unsupported replay version {}", version),
+        Error::Codec(err) => format!("corrupted: {:?}", err),
+    })?;
+    match replay.verify() {
+        Ok(_) => Ok(replay),
+        Err(divergence) => Err(describe(&replay, &divergence)),
+    }
+}
+
+fn main() {
+    let paths: Vec<_> = env::args().skip(1).collect();
+    if paths.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    if paths.is_empty() {
+        eprintln!("error: no replay given");
+        eprintln!();
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let mut failed = false;
+    for path in &paths {
+        match verify(path) {
+            Ok(replay) => println!(
+                "{}: ok, game id {} with users {:?}, {} inputs over {} ticks",
+                path,
+                replay.game_id,
+                replay.players,
+                replay.inputs.len(),
+                replay.end_tick
+            ),
+            Err(reason) => {
+                println!("{}: {}", path, reason);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}